@@ -1,3 +1,19 @@
 pub const BASIS_POINTS_DENOMINATOR: u64 = 10_000;
 
+pub const MAX_ESCROW_TIMEOUT: i64 = 365 * 24 * 60 * 60;
+
 pub const CLAIM_MARKER: &str = "claim";
+
+pub const ALLOWLIST_MARKER: &str = "allowlist";
+
+pub const PURCHASE_MARKER: &str = "purchase";
+
+pub const ORDER: &str = "order";
+
+pub const CONFIG: &str = "config";
+
+pub const AFFILIATE: &str = "affiliate";
+
+pub const PRODUCT_STATS: &str = "product_stats";
+
+pub const REFERRER_STATS: &str = "referrer_stats";
@@ -43,4 +43,124 @@ pub enum FosterStudioError {
     // 6009
     #[msg("Account mismatch")]
     AccountMismatch,
+
+    // 6010
+    #[msg("Invalid quantity")]
+    InvalidQuantity,
+
+    // 6011
+    #[msg("Numerical overflow")]
+    NumericalOverflow,
+
+    // 6012
+    #[msg("Invalid order status")]
+    InvalidOrderStatus,
+
+    // 6013
+    #[msg("Escrow not expired")]
+    EscrowNotExpired,
+
+    // 6014
+    #[msg("Buyer not on allowlist")]
+    NotAllowlisted,
+
+    // 6015
+    #[msg("Missing allowlist marker")]
+    MissingAllowlistMarker,
+
+    // 6016
+    #[msg("No more allowlist allocation")]
+    NoMoreAllocation,
+
+    // 6017
+    #[msg("Missing collection nft")]
+    MissingCollectionNft,
+
+    // 6018
+    #[msg("Missing token program")]
+    MissingTokenProgram,
+
+    // 6019
+    #[msg("Invalid payment option")]
+    InvalidPaymentOption,
+
+    // 6020
+    #[msg("Invalid payment splits")]
+    InvalidPaymentSplits,
+
+    // 6021
+    #[msg("Invalid platform fee")]
+    InvalidPlatformFee,
+
+    // 6022
+    #[msg("Program paused")]
+    ProgramPaused,
+
+    // 6023
+    #[msg("Product paused")]
+    ProductPaused,
+
+    // 6024
+    #[msg("Affiliate not approved")]
+    AffiliateNotApproved,
+
+    // 6025
+    #[msg("Affiliate inactive")]
+    AffiliateInactive,
+
+    // 6026
+    #[msg("Invalid commission")]
+    InvalidCommission,
+
+    // 6027
+    #[msg("Invalid referrer")]
+    InvalidReferrer,
+
+    // 6028
+    #[msg("Wallet purchase limit reached")]
+    NoMoreWalletPurchases,
+
+    // 6029
+    #[msg("Missing purchase marker")]
+    MissingPurchaseMarker,
+
+    // 6030
+    #[msg("Invalid auction")]
+    InvalidAuction,
+
+    // 6031
+    #[msg("Auction not ended")]
+    AuctionNotEnded,
+
+    // 6032
+    #[msg("Invalid pricing curve")]
+    InvalidPricingCurve,
+
+    // 6033
+    #[msg("Price above the buyer's maximum")]
+    PriceTooHigh,
+
+    // 6034
+    #[msg("Product terms changed")]
+    TermsChanged,
+
+    // 6035
+    #[msg("Invalid sale phase")]
+    InvalidSalePhase,
+
+    // 6036
+    #[msg("No active sale phase")]
+    NoActivePhase,
+
+    // 6037
+    #[msg("Invalid variant")]
+    InvalidVariant,
+
+    // 6038
+    #[msg("Product has open escrowed orders")]
+    OpenEscrows,
+
+    // 6039
+    #[msg("Invalid escrow")]
+    InvalidEscrow,
 }
@@ -0,0 +1,245 @@
+use anchor_lang::{prelude::*, solana_program::account_info::next_account_info};
+use anchor_spl::{
+    associated_token::get_associated_token_address_with_program_id,
+    token_2022_extensions::transfer_fee,
+    token_interface::{self, TokenAccount},
+};
+
+use crate::{
+    constants::ORDER,
+    errors::*,
+    payments::{assert_mint, token_program_for, withheld_transfer_fees, Payer},
+    state::{Order, OrderPayment, TransferFeeMode},
+    utils::assert_token_account,
+};
+
+pub fn assert_vault(order: &Account<Order>, mint: &AccountInfo, vault: &AccountInfo) -> Result<()> {
+    let expected_vault =
+        get_associated_token_address_with_program_id(&order.key(), &mint.key(), mint.owner);
+    if vault.key() != expected_vault {
+        msg!(
+            "invalid escrow vault: expected {}, got {}",
+            expected_vault,
+            vault.key()
+        );
+        return Err(AccountMismatch.into());
+    }
+
+    Ok(())
+}
+
+/// lamports or tokens held by the vault of `mint`
+pub fn vault_balance(vault: &AccountInfo, mint: &Pubkey) -> Result<u64> {
+    if *mint == Pubkey::default() {
+        return Ok(vault.lamports());
+    }
+
+    Ok(TokenAccount::try_deserialize(&mut &**vault.try_borrow_data()?)?.amount)
+}
+
+/// amount the vault has to receive for `payment`.
+/// net payments also cover the transfer fees of releasing it
+pub fn vault_amount(payment: &OrderPayment, mint: Option<&AccountInfo>) -> Result<u64> {
+    let Some(mint) = mint else {
+        return payment.total_amount();
+    };
+
+    [
+        payment.amount,
+        payment.referrer_amount,
+        payment.platform_fee,
+    ]
+    .iter()
+    .try_fold(0u64, |total, amount| {
+        let transfer_amount = payment.transfer_fee_mode.transfer_amount(mint, *amount)?;
+        Ok(total
+            .checked_add(transfer_amount)
+            .ok_or(NumericalOverflow)?)
+    })
+}
+
+/// records the `escrowed_amount` the vault received for `payments`, shared in proportion
+/// to their `vault_amounts`, the rounding remainder going to the first payment
+pub fn record_escrow(payments: &mut [OrderPayment], vault_amounts: &[u64], escrowed_amount: u64) {
+    let total = vault_amounts.iter().sum::<u64>().max(1);
+    for (payment, vault_amount) in payments.iter_mut().zip(vault_amounts) {
+        payment.escrowed_amount =
+            (*vault_amount as u128 * escrowed_amount as u128 / total as u128) as u64;
+    }
+    let recorded = payments
+        .iter()
+        .map(|payment| payment.escrowed_amount)
+        .sum::<u64>();
+    if let Some(first) = payments.first_mut() {
+        first.escrowed_amount += escrowed_amount - recorded;
+    }
+}
+
+/// runs `f` with the signer seeds of the order
+pub fn with_order_signer<R>(order: &Order, f: impl FnOnce(&[&[&[u8]]]) -> Result<R>) -> Result<R> {
+    let number = order.number.to_le_bytes();
+    f(&[&[
+        ORDER.as_bytes(),
+        order.product.as_ref(),
+        order.buyer.as_ref(),
+        &number,
+        &[order.bump],
+    ]])
+}
+
+/// payer for the escrowed payment of `mint`, reading the vault from `payment_atas`.
+/// amounts paid by the vault are those of `vault_transfers`, which already account for
+/// the payment's transfer fee mode
+pub fn vault_payer<'a, 'info>(
+    order: &Account<'info, Order>,
+    mint: &Pubkey,
+    payment_atas: &mut impl Iterator<Item = &'a AccountInfo<'info>>,
+    token_program: &AccountInfo<'info>,
+    token_2022_program: Option<&AccountInfo<'info>>,
+    signer_seeds: &'a [&'a [&'a [u8]]],
+) -> Result<Payer<'a, 'info>>
+where
+    'info: 'a,
+{
+    if *mint == Pubkey::default() {
+        return Ok(Payer::Program {
+            from: order.to_account_info(),
+        });
+    }
+
+    let mint_account = next_account_info(payment_atas)?.clone();
+    let vault = next_account_info(payment_atas)?.clone();
+    assert_mint(&mint_account, mint)?;
+    assert_vault(order, &mint_account, &vault)?;
+
+    Ok(Payer::Token {
+        from: vault,
+        authority: order.to_account_info(),
+        token_program: token_program_for(&mint_account, token_program, token_2022_program)?,
+        mint: mint_account,
+        transfer_fee_mode: TransferFeeMode::Gross,
+        signer_seeds,
+    })
+}
+
+/// amounts to transfer out of the vault for `amounts` of `payment`, in order, emptying what
+/// the vault holds for it. net payments add the transfer fee of each transfer, gross payments
+/// share the escrowed tokens in proportion, the recipients bearing the fees.
+/// transfers are capped by the escrowed amount, whatever is left goes with the first one
+pub fn vault_transfers(payer: &Payer, payment: &OrderPayment, amounts: &[u64]) -> Result<Vec<u64>> {
+    let mut transfers = match payer {
+        Payer::Token { mint, .. } if payment.transfer_fee_mode == TransferFeeMode::Net => amounts
+            .iter()
+            .map(|amount| payment.transfer_fee_mode.transfer_amount(mint, *amount))
+            .collect::<Result<Vec<_>>>()?,
+        _ => {
+            let total = amounts.iter().sum::<u64>().max(1);
+            amounts
+                .iter()
+                .map(|amount| {
+                    (*amount as u128 * payment.escrowed_amount as u128 / total as u128) as u64
+                })
+                .collect()
+        }
+    };
+
+    let mut remaining = payment.escrowed_amount;
+    for transfer in transfers.iter_mut() {
+        *transfer = (*transfer).min(remaining);
+        remaining -= *transfer;
+    }
+    if let Some(first) = transfers.first_mut() {
+        *first += remaining;
+    }
+
+    Ok(transfers)
+}
+
+/// closes an emptied vault, returning its rent to `destination`.
+/// vaults holding unexpected tokens are left open so they cannot block the order
+pub fn close_vault<'info>(payer: &Payer<'_, 'info>, destination: AccountInfo<'info>) -> Result<()> {
+    let Payer::Token {
+        from: vault,
+        authority,
+        mint,
+        token_program,
+        signer_seeds,
+        ..
+    } = payer
+    else {
+        return Ok(());
+    };
+
+    let remaining_amount = vault_balance(vault, &mint.key())?;
+    if remaining_amount != 0 {
+        msg!(
+            "vault {} not empty, {} tokens left",
+            vault.key(),
+            remaining_amount
+        );
+        return Ok(());
+    }
+
+    // token-2022 accounts withholding transfer fees cannot be closed, the fees go to the mint
+    if withheld_transfer_fees(vault)? > 0 {
+        transfer_fee::harvest_withheld_tokens_to_mint(
+            CpiContext::new(
+                token_program.clone(),
+                transfer_fee::HarvestWithheldTokensToMint {
+                    token_program_id: token_program.clone(),
+                    mint: mint.clone(),
+                },
+            ),
+            vec![vault.clone()],
+        )?;
+    }
+
+    token_interface::close_account(CpiContext::new_with_signer(
+        token_program.clone(),
+        token_interface::CloseAccount {
+            account: vault.clone(),
+            destination,
+            authority: authority.clone(),
+        },
+        signer_seeds,
+    ))
+}
+
+/// returns every escrowed payment of the order to the buyer
+pub fn refund<'info>(
+    order: &Account<'info, Order>,
+    buyer: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+    token_2022_program: Option<&AccountInfo<'info>>,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> Result<()> {
+    with_order_signer(order, |signer_seeds| {
+        let payment_atas = &mut remaining_accounts.iter();
+        for payment in &order.payments {
+            msg!("");
+            msg!("refunding {} of {}", payment.escrowed_amount, payment.mint);
+            let payer = vault_payer(
+                order,
+                &payment.mint,
+                payment_atas,
+                token_program,
+                token_2022_program,
+                signer_seeds,
+            )?;
+
+            let to = if payment.mint == Pubkey::default() {
+                buyer.clone()
+            } else {
+                let to = next_account_info(payment_atas)?.clone();
+                assert_token_account(&to, &payment.mint, &order.buyer)?;
+                to
+            };
+            // everything escrowed for the payment goes back to the buyer
+            let transfers = vault_transfers(&payer, payment, &[payment.total_amount()?])?;
+            payer.pay(to, transfers[0])?;
+            close_vault(&payer, buyer.clone())?;
+        }
+
+        Ok(())
+    })
+}
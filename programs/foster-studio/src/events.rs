@@ -0,0 +1,68 @@
+use anchor_lang::prelude::*;
+
+use crate::state::{FeeRecipient, MerchProductConfig, OrderPayment};
+
+#[event]
+pub struct ProductConfigured {
+    pub product: Pubkey,
+    pub authority: Pubkey,
+    pub created: bool,
+    pub changes: MerchProductConfig,
+}
+
+#[event]
+pub struct ProductPurchased {
+    pub product: Pubkey,
+    pub order: Pubkey,
+    pub buyer: Pubkey,
+
+    pub quantity: u32,
+    pub variant: Option<u8>,
+    pub payments: Vec<OrderPayment>,
+
+    pub referrer: Option<Pubkey>,
+    pub edition: Option<Pubkey>,
+
+    pub current_supply: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ProductDeleted {
+    pub product: Pubkey,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct OrderReleased {
+    pub order: Pubkey,
+    pub product: Pubkey,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct OrderRefunded {
+    pub order: Pubkey,
+    pub product: Pubkey,
+    pub authority: Pubkey,
+    pub current_supply: u64,
+}
+
+#[event]
+pub struct ConfigUpdated {
+    pub config: Pubkey,
+    pub admin: Pubkey,
+    pub platform_fee_bps: u16,
+    pub fee_recipients: Vec<FeeRecipient>,
+    pub paused: bool,
+}
+
+#[event]
+pub struct AffiliateUpdated {
+    pub affiliate: Pubkey,
+    pub wallet: Pubkey,
+    pub scope: Pubkey,
+    pub commission_bps: Option<u16>,
+    pub active: bool,
+    pub approved: bool,
+}
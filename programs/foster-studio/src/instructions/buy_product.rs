@@ -1,18 +1,71 @@
 use anchor_lang::{prelude::*, solana_program::account_info::next_account_info, system_program};
 use anchor_spl::{
-    metadata::{self, mpl_token_metadata::accounts::MasterEdition, MasterEditionAccount},
-    token::{self, Token},
+    metadata::{
+        self,
+        mpl_token_metadata::{
+            accounts::{MasterEdition, Metadata},
+            types::Collection,
+        },
+        MasterEditionAccount, MetadataAccount,
+    },
+    token::{self, Mint, Token, TokenAccount},
+    token_2022::{self, Token2022},
 };
 use std::ops::Deref;
 
 use crate::{
-    constants::{BASIS_POINTS_DENOMINATOR, CLAIM_MARKER},
+    constants::{
+        ALLOWLIST_MARKER, BASIS_POINTS_DENOMINATOR, CLAIM_MARKER, CONFIG, ORDER, PRODUCT_STATS,
+        PURCHASE_MARKER, REFERRER_STATS,
+    },
     errors::*,
+    escrow,
+    events::ProductPurchased,
     mpl_token_metadata::EditionAccount,
-    state::{MerchProduct, PaymentConfig},
+    payments::{assert_fee_recipient, assert_mint, token_program_for, Payer},
+    state::{
+        Affiliate, CommissionMode, Config, EscrowConfig, MerchProduct, Order, OrderPayment,
+        OrderStatus, PaymentConfig, PhaseGate,
+    },
+    utils::{
+        assert_nft_holder, assert_token_account, assert_token_account_mint, load_or_create_marker,
+        load_or_create_stats, save_marker, save_stats,
+    },
 };
 
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug)]
+pub struct BuyProductArgs {
+    /// sequence number of the order, unique per product and buyer
+    pub order_number: u64,
+    pub quantity: u32,
+    pub variant: Option<u8>,
+    pub payment_option: u8,
+    pub allowlist_proof: Option<AllowlistProof>,
+    pub max_prices: Option<Vec<u64>>,
+    pub expected_terms: Option<ExpectedTerms>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug)]
+pub struct AllowlistProof {
+    pub allocation: u32,
+    pub proof: Vec<[u8; 32]>,
+}
+
+/// product terms the buyer signed for
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ExpectedTerms {
+    /// sha256 of the borsh serialized `product.payments` followed by the borsh serialized
+    /// `amounts` of every variant
+    pub payments_hash: [u8; 32],
+    /// commission rate charged on the purchase: the affiliate's rate, or 0 without referrer
+    pub affiliate_commission_bps: u16,
+    /// price of the active sale phase, 10000 without phases
+    pub price_bps: u16,
+}
+
+#[event_cpi]
 #[derive(Accounts)]
+#[instruction(args: BuyProductArgs)]
 pub struct BuyProduct<'info> {
     #[account(mut)]
     pub buyer: Signer<'info>,
@@ -20,6 +73,23 @@ pub struct BuyProduct<'info> {
     #[account(mut)]
     pub product: Box<Account<'info, MerchProduct>>,
 
+    #[account(seeds = [CONFIG.as_bytes()], bump = config.bump)]
+    pub config: Box<Account<'info, Config>>,
+
+    #[account(
+        init,
+        payer = buyer,
+        space = Order::space(product.payment_count(args.payment_option)),
+        seeds = [
+            ORDER.as_bytes(),
+            product.key().as_ref(),
+            buyer.key().as_ref(),
+            args.order_number.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub order: Box<Account<'info, Order>>,
+
     #[account(
         seeds = [
             MasterEdition::PREFIX.0,
@@ -35,59 +105,214 @@ pub struct BuyProduct<'info> {
     )]
     pub master_edition_pda: Option<Box<Account<'info, MasterEditionAccount>>>,
 
+    /// edition or collection nft held by the buyer
+    pub nft_mint: Option<Box<Account<'info, Mint>>>,
+
+    pub nft_token_account: Option<Box<Account<'info, TokenAccount>>>,
+
+    #[account(
+        seeds = [
+            MasterEdition::PREFIX.0,
+            metadata::ID.as_ref(),
+            nft_mint
+                .as_ref()
+                .map(|nft_mint| nft_mint.key())
+                .unwrap_or_default()
+                .as_ref(),
+            MasterEdition::PREFIX.1
+        ],
+        seeds::program = metadata::ID,
+        bump
+    )]
     pub edition_pda: Option<Box<Account<'info, EditionAccount>>>,
 
-    /// CHECK: handled in buy logic
     #[account(
-        mut,
         seeds = [
-            CLAIM_MARKER.as_bytes(),
-            edition_pda
+            Metadata::PREFIX,
+            metadata::ID.as_ref(),
+            nft_mint
                 .as_ref()
-                .map(|edition_pda| edition_pda.key())
+                .map(|nft_mint| nft_mint.key())
                 .unwrap_or_default()
                 .as_ref()
         ],
+        seeds::program = metadata::ID,
+        bump
+    )]
+    pub nft_metadata: Option<Box<Account<'info, MetadataAccount>>>,
+
+    /// CHECK: handled in buy logic
+    #[account(
+        mut,
+        seeds = [
+            CLAIM_MARKER.as_bytes(),
+            product.claim_scope(product.key()).as_ref(),
+            if product.linked_master_nft.is_some() {
+                edition_pda.as_ref().map(|edition_pda| edition_pda.key())
+            } else {
+                nft_mint.as_ref().map(|nft_mint| nft_mint.key())
+            }
+            .unwrap_or_default()
+            .as_ref()
+        ],
         bump
     )]
     pub claim_marker: Option<UncheckedAccount<'info>>,
 
+    /// CHECK: handled in buy logic, required to create the claim marker
+    #[account(
+        seeds = [
+            CLAIM_MARKER.as_bytes(),
+            if product.linked_master_nft.is_some() {
+                edition_pda.as_ref().map(|edition_pda| edition_pda.key())
+            } else {
+                nft_mint.as_ref().map(|nft_mint| nft_mint.key())
+            }
+            .unwrap_or_default()
+            .as_ref()
+        ],
+        bump
+    )]
+    pub legacy_claim_marker: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: handled in buy logic
+    #[account(
+        mut,
+        seeds = [
+            ALLOWLIST_MARKER.as_bytes(),
+            product.key().as_ref(),
+            buyer.key().as_ref()
+        ],
+        bump
+    )]
+    pub allowlist_marker: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: handled in buy logic
+    #[account(
+        mut,
+        seeds = [
+            PURCHASE_MARKER.as_bytes(),
+            product.key().as_ref(),
+            buyer.key().as_ref()
+        ],
+        bump
+    )]
+    pub purchase_marker: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: checked against the active sale phase in buy logic
+    #[account(mut)]
+    pub phase_purchase_marker: Option<UncheckedAccount<'info>>,
+
     #[account(mut)]
     pub referrer: Option<AccountInfo<'info>>,
 
+    /// registration of the referrer, with the product or its seller
+    pub affiliate: Option<Box<Account<'info, Affiliate>>>,
+
+    /// CHECK: created in buy logic
+    #[account(
+        mut,
+        seeds = [PRODUCT_STATS.as_bytes(), product.key().as_ref()],
+        bump
+    )]
+    pub product_stats: UncheckedAccount<'info>,
+
+    /// CHECK: created in buy logic, required with a referrer
+    #[account(
+        mut,
+        seeds = [
+            REFERRER_STATS.as_bytes(),
+            referrer.as_ref().map(|referrer| referrer.key()).unwrap_or_default().as_ref()
+        ],
+        bump
+    )]
+    pub referrer_stats: Option<UncheckedAccount<'info>>,
+
     #[account(address = system_program::ID)]
     pub system_program: Program<'info, System>,
 
     #[account(address = token::ID)]
     pub token_program: Program<'info, Token>,
+
+    #[account(address = token_2022::ID)]
+    pub token_2022_program: Option<Program<'info, Token2022>>,
     // remaining accounts:
-    // in order of product.payments,
-    // for sol payments: recipient
-    // for token payments: from ata, to ata, [referrer ata, if there is a referrer]
+    // in order of the payments of the selected payment option,
+    // for sol payments: recipient, or every split recipient
+    // for token payments: mint, from ata, to ata or every split recipient ata, [referrer ata, if there is a referrer]
+    // followed by [fee recipient, if config charges a platform fee for the mint]
+    // if product.escrow is set:
+    // for sol payments: none, the order holds the lamports
+    // for token payments: mint, from ata, order ata
 }
 
-pub fn buy_product<'info>(ctx: Context<'_, '_, '_, 'info, BuyProduct<'info>>) -> Result<()> {
+pub fn buy_product<'info>(
+    ctx: Context<'_, '_, '_, 'info, BuyProduct<'info>>,
+    args: BuyProductArgs,
+) -> Result<()> {
+    let BuyProductArgs {
+        order_number,
+        quantity,
+        variant,
+        payment_option,
+        allowlist_proof,
+        max_prices,
+        expected_terms,
+    } = args;
+    if quantity == 0 {
+        msg!("quantity must be greater than 0");
+        return Err(InvalidQuantity.into());
+    }
+
     let BuyProduct {
         ref buyer,
         ref mut product,
+        ref config,
+        ref mut order,
         ref master_edition_pda,
+        ref nft_mint,
+        ref nft_token_account,
         ref edition_pda,
+        ref nft_metadata,
         ref mut claim_marker,
+        ref legacy_claim_marker,
+        ref mut allowlist_marker,
+        ref mut purchase_marker,
+        ref mut phase_purchase_marker,
         ref referrer,
+        ref affiliate,
+        ref product_stats,
+        ref referrer_stats,
         system_program: ref system_program_account,
         ref token_program,
+        ref token_2022_program,
+        event_authority: _,
+        program: _,
     } = ctx.accounts;
 
-    // check start and end time
-    product.assert_is_live()?;
+    // check pauses, start and end time, and pick the active sale phase
+    config.assert_not_paused()?;
+    let phase = product.assert_is_live()?;
+    product.assert_variant(variant)?;
+    let gate = phase.as_ref().map(|(_, phase)| phase.gate);
+
+    // without phases, linked nfts and allowlists gate every purchase
+    let nft_gated = matches!(gate, None | Some(PhaseGate::Holders));
+    let allowlist_gated = matches!(gate, None | Some(PhaseGate::Allowlist));
 
-    // verify linked master edition
-    if product.linked_master_nft.is_some() {
+    // verify linked master edition, claims are tracked per edition
+    let claim_seed = if !nft_gated {
+        None
+    } else if product.linked_master_nft.is_some() {
         let master_edition_key = master_edition_pda
             .as_ref()
             .ok_or(MissingMasterEdition)?
             .key();
+        let nft_mint = nft_mint.as_ref().ok_or(MissingEdition)?;
+        let nft_token_account = nft_token_account.as_ref().ok_or(MissingEdition)?;
         let edition_pda = edition_pda.as_ref().ok_or(MissingEdition)?;
+        assert_nft_holder(nft_token_account, &nft_mint.key(), &buyer.key())?;
+
         let edition_parent = edition_pda.parent;
         if master_edition_key != edition_parent {
             msg!(
@@ -98,38 +323,145 @@ pub fn buy_product<'info>(ctx: Context<'_, '_, '_, 'info, BuyProduct<'info>>) ->
             return Err(AccountMismatch.into());
         }
 
-        // assert claim count
+        Some(edition_pda.key())
+    }
+    // verify linked collection, claims are tracked per nft mint
+    else if let Some(linked_collection) = product.linked_collection {
+        let nft_mint = nft_mint.as_ref().ok_or(MissingCollectionNft)?;
+        let nft_metadata = nft_metadata.as_ref().ok_or(MissingCollectionNft)?;
+        let nft_token_account = nft_token_account.as_ref().ok_or(MissingCollectionNft)?;
+        assert_nft_holder(nft_token_account, &nft_mint.key(), &buyer.key())?;
+
+        match &nft_metadata.collection {
+            Some(Collection {
+                verified: true,
+                key,
+            }) if *key == linked_collection => {}
+            collection => {
+                msg!(
+                    "collection mismatch: expected verified {}, got {:?}",
+                    linked_collection,
+                    collection
+                );
+                return Err(AccountMismatch.into());
+            }
+        }
+
+        Some(nft_mint.key())
+    } else {
+        None
+    };
+
+    // assert claim count
+    if let Some(claim_seed) = claim_seed {
         let claim_marker = claim_marker.as_ref().ok_or(MissingClaimMarker)?;
-        let mut claim_count = 0u32;
-        if *claim_marker.owner != crate::ID {
-            let claim_count_len = claim_count.try_to_vec()?.as_slice().len();
-
-            system_program::create_account(
-                CpiContext::new(
-                    system_program_account.to_account_info(),
-                    system_program::CreateAccount {
-                        from: buyer.to_account_info(),
-                        to: claim_marker.to_account_info(),
-                    },
-                )
-                .with_signer(&[&[
-                    CLAIM_MARKER.as_bytes(),
-                    edition_pda.key().as_ref(),
-                    &[ctx.bumps.claim_marker.unwrap_or_default()],
-                ]]),
-                Rent::get()?.minimum_balance(claim_count_len),
-                claim_count_len as u64,
-                &crate::ID,
-            )?;
+
+        // claims made before markers were scoped are shared by every product linked to the nft,
+        // new markers start from them
+        let legacy_claim_count = if *claim_marker.owner == crate::ID {
+            0
         } else {
-            claim_count = u32::deserialize(&mut &(**claim_marker.try_borrow_data()?))?;
+            let legacy_claim_marker = legacy_claim_marker.as_ref().ok_or(MissingClaimMarker)?;
+            if *legacy_claim_marker.owner == crate::ID {
+                u32::deserialize(&mut &(**legacy_claim_marker.try_borrow_data()?))?
+            } else {
+                0
+            }
+        };
+        let mut claim_count = load_or_create_marker(
+            claim_marker,
+            buyer,
+            system_program_account,
+            &[
+                CLAIM_MARKER.as_bytes(),
+                product.claim_scope(product.key()).as_ref(),
+                claim_seed.as_ref(),
+                &[ctx.bumps.claim_marker.unwrap_or_default()],
+            ],
+        )?;
+        claim_count = product.process_user_claim(claim_count + legacy_claim_count, quantity)?;
+        save_marker(claim_marker, claim_count)?;
+    }
+
+    // verify allowlist
+    let mut allowlist_counted = false;
+    if allowlist_gated && product.allowlist_root.is_some() {
+        let AllowlistProof { allocation, proof } =
+            allowlist_proof.as_ref().ok_or(NotAllowlisted)?;
+        product.assert_allowlisted(&buyer.key(), *allocation, proof)?;
+
+        // assert allocation
+        if *allocation > 0 {
+            let allowlist_marker = allowlist_marker.as_ref().ok_or(MissingAllowlistMarker)?;
+            let mut purchase_count = load_or_create_marker(
+                allowlist_marker,
+                buyer,
+                system_program_account,
+                &[
+                    ALLOWLIST_MARKER.as_bytes(),
+                    product.key().as_ref(),
+                    buyer.key().as_ref(),
+                    &[ctx.bumps.allowlist_marker.unwrap_or_default()],
+                ],
+            )?;
+            purchase_count =
+                product.process_allowlist_claim(purchase_count, *allocation, quantity)?;
+            save_marker(allowlist_marker, purchase_count)?;
+            allowlist_counted = true;
         }
+    }
 
-        claim_count = product.process_user_claim(claim_count)?;
+    // assert wallet limit
+    let wallet_counted = product.max_per_wallet > 0;
+    if wallet_counted {
+        let purchase_marker = purchase_marker.as_ref().ok_or(MissingPurchaseMarker)?;
+        let mut purchase_count = load_or_create_marker(
+            purchase_marker,
+            buyer,
+            system_program_account,
+            &[
+                PURCHASE_MARKER.as_bytes(),
+                product.key().as_ref(),
+                buyer.key().as_ref(),
+                &[ctx.bumps.purchase_marker.unwrap_or_default()],
+            ],
+        )?;
+        purchase_count = product.process_wallet_purchase(purchase_count, quantity)?;
+        save_marker(purchase_marker, purchase_count)?;
+    }
 
-        claim_marker
-            .try_borrow_mut_data()?
-            .copy_from_slice(&claim_count.try_to_vec()?);
+    // assert phase wallet limit, tracked per phase
+    let phase_counted = phase.as_ref().filter(|(_, phase)| phase.max_per_wallet > 0);
+    if let Some((index, phase)) = phase_counted {
+        let phase_purchase_marker = phase_purchase_marker
+            .as_ref()
+            .ok_or(MissingPurchaseMarker)?;
+        let product_key = product.key();
+        let buyer_key = buyer.key();
+        let seeds = [
+            PURCHASE_MARKER.as_bytes(),
+            product_key.as_ref(),
+            buyer_key.as_ref(),
+            &[*index],
+        ];
+        let (expected_marker, bump) = Pubkey::find_program_address(&seeds, &crate::ID);
+        if phase_purchase_marker.key() != expected_marker {
+            msg!(
+                "invalid phase purchase marker: expected {}, got {}",
+                expected_marker,
+                phase_purchase_marker.key()
+            );
+            return Err(AccountMismatch.into());
+        }
+
+        let mut purchase_count = load_or_create_marker(
+            phase_purchase_marker,
+            buyer,
+            system_program_account,
+            &[&seeds[..], &[&[bump]]].concat(),
+        )?;
+        purchase_count = phase.process_wallet_purchase(purchase_count, quantity)?;
+        save_marker(phase_purchase_marker, purchase_count)?;
     }
 
     // check supply
@@ -137,116 +469,323 @@ pub fn buy_product<'info>(ctx: Context<'_, '_, '_, 'info, BuyProduct<'info>>) ->
         master_edition_pda
             .as_ref()
             .map(|master_edition| master_edition.deref().deref().deref()),
+        quantity,
+        variant,
     )?;
 
-    // process payments
-    let payment_atas = &mut ctx.remaining_accounts.iter();
-    for PaymentConfig {
-        tag,
-        mint,
-        amount,
-        recipient,
-    } in &product.payments
+    // only approved affiliates are paid, at their negotiated rate
+    let commission_bps = match referrer {
+        Some(referrer) => {
+            product.assert_valid_referrer(&referrer.key(), &buyer.key())?;
+            let affiliate = affiliate.as_ref().ok_or_else(|| {
+                msg!("referrer {} is not a registered affiliate", referrer.key());
+                AffiliateNotApproved
+            })?;
+            affiliate.commission_bps(&referrer.key(), product)?
+        }
+        None => 0,
+    };
+
+    // the seller cannot change the price, phase price or commission under a pending purchase
+    if let Some(ExpectedTerms {
+        payments_hash,
+        affiliate_commission_bps,
+        price_bps,
+    }) = &expected_terms
     {
-        let referrer_amount =
-            (*amount * (product.affiliate_commission_bps as u64)) / BASIS_POINTS_DENOMINATOR;
+        let phase_price_bps = phase
+            .as_ref()
+            .map_or(BASIS_POINTS_DENOMINATOR as u16, |(_, phase)| {
+                phase.price_bps
+            });
+        product.assert_terms(
+            payments_hash,
+            *affiliate_commission_bps,
+            commission_bps,
+            *price_bps,
+            phase_price_bps,
+        )?;
+    }
 
-        msg!("");
-        // sol transfer
-        if *mint == Pubkey::default() {
-            let to = next_account_info(payment_atas)?.clone();
-            msg!("processing sol payment: {}", tag);
+    // process payments
+    let created_at = Clock::get()?.unix_timestamp;
+    let payments = product.payment_option(payment_option, variant)?;
+    if let Some(max_prices) = &max_prices {
+        if max_prices.len() != payments.len() {
             msg!(
-                "from {} to {} for {} lamports",
-                buyer.key(),
-                to.key(),
-                amount,
+                "expected {} max prices, got {}",
+                payments.len(),
+                max_prices.len()
             );
-            if *recipient != to.key() {
-                msg!(
-                    "invalid recipient: expected {}, got {}",
-                    recipient,
-                    to.key()
-                );
-                return Err(AccountMismatch.into());
+            return Err(PriceTooHigh.into());
+        }
+    }
+    let mut order_payments = Vec::with_capacity(payments.len());
+    let mut sale_amounts = Vec::with_capacity(payments.len());
+    let payment_atas = &mut ctx.remaining_accounts.iter();
+    for (i, payment) in payments.iter().enumerate() {
+        let PaymentConfig {
+            tag,
+            mint,
+            transfer_fee_mode,
+            ..
+        } = payment;
+        let (mut amount, mut unit_price) = payment.quote(
+            product.current_supply,
+            quantity,
+            product.sale_start_at,
+            created_at,
+        )?;
+        if let Some((_, phase)) = &phase {
+            amount = phase.price(amount)?;
+            unit_price = phase.price(unit_price)?;
+        }
+        msg!("unit price of {}: {}", tag, unit_price);
+        // the price moved since the buyer signed
+        if let Some(max_price) = max_prices.as_ref().map(|max_prices| max_prices[i]) {
+            if unit_price > max_price {
+                msg!("unit price {} above maximum {}", unit_price, max_price);
+                return Err(PriceTooHigh.into());
             }
+        }
+        let referrer_amount = amount
+            .checked_mul(commission_bps as u64)
+            .ok_or(NumericalOverflow)?
+            / BASIS_POINTS_DENOMINATOR;
+        sale_amounts.push((*mint, amount));
+        let platform_fee = config.platform_fee(mint, amount)?;
+        // share of the payment going to the seller
+        let seller_amount = match product.commission_mode {
+            CommissionMode::OnTop => amount,
+            CommissionMode::FromSeller => amount
+                .checked_sub(referrer_amount)
+                .ok_or(NumericalOverflow)?,
+        };
+        let split_amounts = payment.split_amount(seller_amount)?;
+        let first_order_payment = order_payments.len();
+        // the referral commission and platform fee are recorded with the first recipient
+        for (i, (recipient, amount)) in split_amounts.iter().enumerate() {
+            let (fee_recipient, platform_fee) = match platform_fee {
+                Some(platform_fee) if i == 0 => platform_fee,
+                _ => Default::default(),
+            };
+            order_payments.push(OrderPayment {
+                mint: *mint,
+                recipient: *recipient,
+                unit_price,
+                amount: *amount,
+                referrer_amount: if i == 0 { referrer_amount } else { 0 },
+                platform_fee,
+                fee_recipient,
+                transfer_fee_mode: *transfer_fee_mode,
+                escrowed_amount: 0,
+            });
+        }
 
-            system_program::transfer(
-                CpiContext::new(
-                    system_program_account.to_account_info(),
-                    system_program::Transfer {
-                        from: buyer.to_account_info(),
-                        to,
-                    },
-                ),
-                *amount,
-            )?;
+        msg!("");
+        let is_sol = *mint == Pubkey::default();
+        let (payer, mint_account) = if is_sol {
+            msg!("processing sol payment: {}", tag);
+            let payer = Payer::System {
+                from: buyer.to_account_info(),
+                system_program: system_program_account.to_account_info(),
+            };
+            (payer, None)
+        } else {
+            msg!("processing token payment: {}", tag);
+            let mint_account = next_account_info(payment_atas)?.clone();
+            let from = next_account_info(payment_atas)?.clone();
+            assert_mint(&mint_account, mint)?;
+            assert_token_account(&from, mint, &buyer.key())?;
+
+            let payer = Payer::Token {
+                from,
+                authority: buyer.to_account_info(),
+                mint: mint_account.clone(),
+                token_program: token_program_for(
+                    &mint_account,
+                    token_program,
+                    token_2022_program.as_ref().map(|program| program.as_ref()),
+                )?,
+                transfer_fee_mode: *transfer_fee_mode,
+                signer_seeds: &[],
+            };
+            (payer, Some(mint_account))
+        };
+
+        // escrowed payment, held by the order until it is released or refunded
+        if product.escrow.is_some() {
+            let vault = match &mint_account {
+                // sol is held by the order itself
+                None => order.to_account_info(),
+                Some(mint_account) => {
+                    let vault = next_account_info(payment_atas)?.clone();
+                    escrow::assert_vault(order, mint_account, &vault)?;
+                    vault
+                }
+            };
+
+            let escrowed_payments = &mut order_payments[first_order_payment..];
+            let vault_amounts = escrowed_payments
+                .iter()
+                .map(|order_payment| escrow::vault_amount(order_payment, mint_account.as_ref()))
+                .collect::<Result<Vec<_>>>()?;
+            let escrow_amount = vault_amounts
+                .iter()
+                .try_fold(0u64, |total, amount| total.checked_add(*amount))
+                .ok_or(NumericalOverflow)?;
+
+            // gross payments reach the vault minus the transfer fee, the order records what arrived
+            msg!("escrowing payment in {}", vault.key());
+            let balance = escrow::vault_balance(&vault, mint)?;
+            payer.pay(vault.clone(), escrow_amount)?;
+            let escrowed_amount = escrow::vault_balance(&vault, mint)?
+                .checked_sub(balance)
+                .ok_or(NumericalOverflow)?;
+            escrow::record_escrow(escrowed_payments, &vault_amounts, escrowed_amount);
+        } else {
+            for (recipient, amount) in split_amounts {
+                let to = next_account_info(payment_atas)?.clone();
+                if recipient != to.key() {
+                    msg!(
+                        "invalid recipient: expected {}, got {}",
+                        recipient,
+                        to.key()
+                    );
+                    return Err(AccountMismatch.into());
+                }
+                if !is_sol {
+                    assert_token_account_mint(&to, mint)?;
+                }
+                payer.pay(to, amount)?;
+            }
 
             // process referrer payments
             if let Some(referrer) = referrer {
-                msg!("referral payment: {} lamports", referrer_amount);
-                system_program::transfer(
-                    CpiContext::new(
-                        system_program_account.to_account_info(),
-                        system_program::Transfer {
-                            from: buyer.to_account_info(),
-                            to: referrer.to_account_info(),
-                        },
-                    ),
-                    referrer_amount,
-                )?;
+                let referrer_account = if is_sol {
+                    referrer.to_account_info()
+                } else {
+                    // owned by the referrer, so never by the buyer
+                    let referrer_ata = next_account_info(payment_atas)?.clone();
+                    assert_token_account(&referrer_ata, mint, &referrer.key())?;
+                    product.assert_not_recipient(&referrer_ata.key())?;
+                    referrer_ata
+                };
+                msg!("referral payment to {}", referrer_account.key());
+                payer.pay(referrer_account, referrer_amount)?;
             }
-        }
-        // token payment
-        else {
-            let from = next_account_info(payment_atas)?.clone();
-            let to = next_account_info(payment_atas)?.clone();
-            msg!("processing token payment: {}", tag);
-            msg!(
-                "from {} to {} for {} {}",
-                from.key(),
-                to.key(),
-                amount,
-                mint,
-            );
 
-            token::transfer(
-                CpiContext::new(
-                    token_program.to_account_info(),
-                    token::Transfer {
-                        from: from.clone(),
-                        to,
-                        authority: buyer.to_account_info(),
-                    },
-                ),
-                *amount,
-            )?;
-
-            // process referrer payments
-            if referrer.is_some() {
-                let referrer_ata = next_account_info(payment_atas)?.clone();
-                msg!(
-                    "referral payment: {} tokens to {}",
-                    referrer_amount,
-                    referrer_ata.key()
-                );
-                token::transfer(
-                    CpiContext::new(
-                        token_program.to_account_info(),
-                        token::Transfer {
-                            from,
-                            to: referrer_ata,
-                            authority: buyer.to_account_info(),
-                        },
-                    ),
-                    referrer_amount,
-                )?;
+            // process platform fee
+            if let Some((fee_recipient, platform_fee)) = platform_fee {
+                let fee_account = next_account_info(payment_atas)?.clone();
+                assert_fee_recipient(&fee_account, &fee_recipient, mint)?;
+                msg!("platform fee to {}", fee_account.key());
+                payer.pay(fee_account, platform_fee)?;
             }
         }
     }
 
+    // record order
+    let (status, escrow_expires_at) = match product.escrow {
+        Some(EscrowConfig { timeout }) => (
+            OrderStatus::Escrowed,
+            Some(created_at.checked_add(timeout).ok_or(NumericalOverflow)?),
+        ),
+        None => (OrderStatus::Paid, None),
+    };
+    if status == OrderStatus::Escrowed {
+        product.open_escrows += 1;
+    }
+    order.set_inner(Order {
+        product: product.key(),
+        buyer: buyer.key(),
+        number: order_number,
+        bump: ctx.bumps.order,
+
+        quantity,
+        variant,
+        payment_option,
+        payments: order_payments,
+
+        referrer: referrer.as_ref().map(|referrer| referrer.key()),
+        edition: claim_seed,
+        claim_scope: claim_seed.map(|_| product.claim_scope(product.key())),
+        allowlist_counted,
+        wallet_counted,
+        phase_counted: phase_counted.map(|(index, _)| *index),
+
+        created_at,
+
+        status,
+        escrow_expires_at,
+    });
+
+    // record sales stats
+    let product_key = product.key();
+    let mut product_stats_data = load_or_create_stats(
+        product_stats,
+        buyer,
+        system_program_account,
+        &[
+            PRODUCT_STATS.as_bytes(),
+            product_key.as_ref(),
+            &[ctx.bumps.product_stats],
+        ],
+        product_key,
+    )?;
+    product_stats_data.record_sale(quantity, &sale_amounts, created_at);
+    save_stats(
+        product_stats,
+        buyer,
+        system_program_account,
+        &product_stats_data,
+    )?;
+
+    if let Some(referrer) = referrer {
+        let referrer_stats = referrer_stats.as_ref().ok_or_else(|| {
+            msg!("missing referrer stats for {}", referrer.key());
+            AccountMismatch
+        })?;
+        let referrer_key = referrer.key();
+        let mut referrer_stats_data = load_or_create_stats(
+            referrer_stats,
+            buyer,
+            system_program_account,
+            &[
+                REFERRER_STATS.as_bytes(),
+                referrer_key.as_ref(),
+                &[ctx.bumps.referrer_stats.unwrap_or_default()],
+            ],
+            referrer_key,
+        )?;
+        referrer_stats_data.record_sale(quantity, &sale_amounts, created_at);
+        save_stats(
+            referrer_stats,
+            buyer,
+            system_program_account,
+            &referrer_stats_data,
+        )?;
+    }
+
     // increment supply
-    product.process_claim();
+    product.process_claim(quantity, variant);
+
+    let event = ProductPurchased {
+        product: product.key(),
+        order: order.key(),
+        buyer: order.buyer,
+
+        quantity,
+        variant,
+        payments: order.payments.clone(),
+
+        referrer: order.referrer,
+        edition: order.edition,
+
+        current_supply: product.current_supply,
+        timestamp: order.created_at,
+    };
+    emit_cpi!(event);
 
     Ok(())
 }
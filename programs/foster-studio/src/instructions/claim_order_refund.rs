@@ -0,0 +1,143 @@
+use anchor_lang::prelude::*;
+use anchor_spl::{
+    token::{self, Token},
+    token_2022::{self, Token2022},
+};
+
+use crate::{
+    constants::{ALLOWLIST_MARKER, CLAIM_MARKER, PURCHASE_MARKER},
+    errors::*,
+    escrow,
+    events::OrderRefunded,
+    instructions::{restore_claims, restore_purchases},
+    state::{MerchProduct, Order, OrderStatus},
+};
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct ClaimOrderRefund<'info> {
+    #[account(mut)]
+    pub buyer: Signer<'info>,
+
+    #[account(mut)]
+    pub product: Box<Account<'info, MerchProduct>>,
+
+    #[account(mut, has_one = product, has_one = buyer)]
+    pub order: Box<Account<'info, Order>>,
+
+    /// CHECK: handled in refund logic
+    #[account(
+        mut,
+        seeds = [
+            CLAIM_MARKER.as_bytes(),
+            order.claim_scope.unwrap_or_default().as_ref(),
+            order.edition.unwrap_or_default().as_ref()
+        ],
+        bump
+    )]
+    pub claim_marker: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: handled in refund logic
+    #[account(
+        mut,
+        seeds = [
+            ALLOWLIST_MARKER.as_bytes(),
+            order.product.as_ref(),
+            order.buyer.as_ref()
+        ],
+        bump
+    )]
+    pub allowlist_marker: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: handled in refund logic
+    #[account(
+        mut,
+        seeds = [
+            PURCHASE_MARKER.as_bytes(),
+            order.product.as_ref(),
+            order.buyer.as_ref()
+        ],
+        bump
+    )]
+    pub purchase_marker: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: handled in refund logic
+    #[account(
+        mut,
+        seeds = [
+            PURCHASE_MARKER.as_bytes(),
+            order.product.as_ref(),
+            order.buyer.as_ref(),
+            &[order.phase_counted.unwrap_or_default()]
+        ],
+        bump
+    )]
+    pub phase_purchase_marker: Option<UncheckedAccount<'info>>,
+
+    #[account(address = token::ID)]
+    pub token_program: Program<'info, Token>,
+
+    #[account(address = token_2022::ID)]
+    pub token_2022_program: Option<Program<'info, Token2022>>,
+    // remaining accounts:
+    // in order of order.payments,
+    // for sol payments: none
+    // for token payments: mint, order ata, buyer ata
+}
+
+pub fn claim_order_refund<'info>(
+    ctx: Context<'_, '_, '_, 'info, ClaimOrderRefund<'info>>,
+) -> Result<()> {
+    let ClaimOrderRefund {
+        buyer,
+        ref mut product,
+        ref mut order,
+        claim_marker,
+        allowlist_marker,
+        purchase_marker,
+        phase_purchase_marker,
+        token_program,
+        token_2022_program,
+        event_authority: _,
+        program: _,
+    } = ctx.accounts;
+
+    order.assert_status(OrderStatus::Escrowed)?;
+
+    // buyers can only reclaim their payment once the seller had time to fulfill the order
+    let now = Clock::get()?.unix_timestamp;
+    let expires_at = order.escrow_expires_at.ok_or(EscrowNotExpired)?;
+    if now < expires_at {
+        msg!("escrow expires at {}, now = {}", expires_at, now);
+        return Err(EscrowNotExpired.into());
+    }
+
+    escrow::refund(
+        order,
+        buyer,
+        token_program,
+        token_2022_program.as_ref().map(|program| program.as_ref()),
+        ctx.remaining_accounts,
+    )?;
+    restore_claims(product, order, claim_marker.as_ref())?;
+    restore_purchases(
+        order,
+        allowlist_marker.as_ref(),
+        purchase_marker.as_ref(),
+        phase_purchase_marker.as_ref(),
+    )?;
+
+    order.status = OrderStatus::Refunded;
+    product.process_escrow_closed();
+    product.process_refund(order.quantity, order.variant);
+
+    let event = OrderRefunded {
+        order: order.key(),
+        product: product.key(),
+        authority: buyer.key(),
+        current_supply: product.current_supply,
+    };
+    emit_cpi!(event);
+
+    Ok(())
+}
@@ -3,9 +3,11 @@ use std::cmp::Ordering;
 
 use crate::{
     errors::*,
+    events::ProductConfigured,
     state::{MerchProduct, MerchProductConfig},
 };
 
+#[event_cpi]
 #[derive(Accounts)]
 pub struct ConfigureProduct<'info> {
     #[account(mut)]
@@ -27,12 +29,16 @@ pub fn configure_product<'info>(
         payer,
         product,
         system_program: _,
+        event_authority: _,
+        program: _,
     } = &ctx.accounts;
     let rent = Rent::get()?;
 
     // load product
-    let serialized_product = if *product.owner != crate::ID {
-        let product_data = product_config.to_product(product.key())?;
+    let (serialized_product, event) = if *product.owner != crate::ID {
+        let mut product_data = product_config.to_product(product.key())?;
+        product_data.update_claim_pool_scope(None, &payer.key());
+        product_data.validate()?;
         let serialized_product = product_data.serialize()?;
         let serialized_product_len = serialized_product.len();
 
@@ -50,7 +56,13 @@ pub fn configure_product<'info>(
             &crate::ID,
         )?;
 
-        serialized_product
+        let event = ProductConfigured {
+            product: product.key(),
+            authority: product_data.authority,
+            created: true,
+            changes: MerchProductConfig::diff(None, &product_data),
+        };
+        (serialized_product, event)
     } else {
         let product_data = MerchProduct::deserialize(
             &mut &(**product.try_borrow_data()?)[MerchProduct::DISCRIMINATOR.len()..],
@@ -60,7 +72,17 @@ pub fn configure_product<'info>(
         }
 
         // update product
-        product_config.update_product(product_data).serialize()?
+        let mut updated_product = product_config.update_product(product_data.clone());
+        updated_product.update_claim_pool_scope(product_data.claim_pool, &payer.key());
+        updated_product.assert_variants_kept(&product_data)?;
+        updated_product.validate()?;
+        let event = ProductConfigured {
+            product: product.key(),
+            authority: updated_product.authority,
+            created: false,
+            changes: MerchProductConfig::diff(Some(&product_data), &updated_product),
+        };
+        (updated_product.serialize()?, event)
     };
 
     // save product
@@ -99,5 +121,7 @@ pub fn configure_product<'info>(
         .try_borrow_mut_data()?
         .copy_from_slice(&serialized_product);
 
+    emit_cpi!(event);
+
     Ok(())
 }
@@ -1,7 +1,8 @@
 use anchor_lang::prelude::*;
 
-use crate::{errors::*, state::MerchProduct};
+use crate::{errors::*, events::ProductDeleted, state::MerchProduct};
 
+#[event_cpi]
 #[derive(Accounts)]
 pub struct DeleteProduct<'info> {
     #[account(mut)]
@@ -12,7 +13,12 @@ pub struct DeleteProduct<'info> {
 }
 
 pub fn delete_product(ctx: Context<DeleteProduct<'_>>) -> Result<()> {
-    let DeleteProduct { authority, product } = &ctx.accounts;
+    let DeleteProduct {
+        authority,
+        product,
+        event_authority: _,
+        program: _,
+    } = &ctx.accounts;
     if product.authority != authority.key() {
         msg!(
             "invalid authority: expected {}, got {}",
@@ -22,5 +28,16 @@ pub fn delete_product(ctx: Context<DeleteProduct<'_>>) -> Result<()> {
         return Err(IncorrectAuthority.into());
     }
 
+    // escrowed orders need the product to be released or refunded
+    if product.open_escrows > 0 {
+        msg!("product has {} open escrowed orders", product.open_escrows);
+        return Err(OpenEscrows.into());
+    }
+
+    emit_cpi!(ProductDeleted {
+        product: product.key(),
+        authority: authority.key(),
+    });
+
     Ok(())
 }
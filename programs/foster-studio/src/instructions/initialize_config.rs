@@ -0,0 +1,76 @@
+use anchor_lang::{prelude::*, solana_program::bpf_loader_upgradeable, system_program};
+
+use crate::{
+    constants::CONFIG,
+    errors::*,
+    events::ConfigUpdated,
+    state::{Config, ConfigArgs},
+};
+
+#[event_cpi]
+#[derive(Accounts)]
+#[instruction(args: ConfigArgs)]
+pub struct InitializeConfig<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = Config::space(args.fee_recipients.as_ref().map_or(0, Vec::len)),
+        seeds = [CONFIG.as_bytes()],
+        bump
+    )]
+    pub config: Box<Account<'info, Config>>,
+
+    #[account(
+        seeds = [crate::ID.as_ref()],
+        bump,
+        seeds::program = bpf_loader_upgradeable::ID
+    )]
+    pub program_data: Account<'info, ProgramData>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+pub fn initialize_config(ctx: Context<InitializeConfig>, args: ConfigArgs) -> Result<()> {
+    let InitializeConfig {
+        authority,
+        config,
+        program_data,
+        system_program: _,
+        event_authority: _,
+        program: _,
+    } = ctx.accounts;
+
+    // only the upgrade authority can set up the program
+    if program_data.upgrade_authority_address != Some(authority.key()) {
+        msg!(
+            "invalid authority: expected {:?}, got {}",
+            program_data.upgrade_authority_address,
+            authority.key()
+        );
+        return Err(IncorrectAuthority.into());
+    }
+
+    let new_config = args.update_config(&Config {
+        admin: authority.key(),
+        platform_fee_bps: 0,
+        fee_recipients: vec![],
+        paused: false,
+        bump: ctx.bumps.config,
+    });
+    new_config.validate()?;
+    config.set_inner(new_config);
+
+    emit_cpi!(ConfigUpdated {
+        config: config.key(),
+        admin: config.admin,
+        platform_fee_bps: config.platform_fee_bps,
+        fee_recipients: config.fee_recipients.clone(),
+        paused: config.paused,
+    });
+
+    Ok(())
+}
@@ -8,3 +8,10 @@ macro_rules! instruction {
 instruction!(configure_product);
 instruction!(buy_product);
 instruction!(delete_product);
+instruction!(release_order);
+instruction!(refund_order);
+instruction!(claim_order_refund);
+instruction!(initialize_config);
+instruction!(update_config);
+instruction!(register_affiliate);
+instruction!(update_affiliate);
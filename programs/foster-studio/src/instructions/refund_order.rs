@@ -0,0 +1,265 @@
+use anchor_lang::{prelude::*, solana_program::account_info::next_account_info, system_program};
+use anchor_spl::{
+    token::{self, Token},
+    token_2022::{self, Token2022},
+};
+
+use crate::{
+    constants::{ALLOWLIST_MARKER, CLAIM_MARKER, PURCHASE_MARKER},
+    errors::*,
+    escrow,
+    events::OrderRefunded,
+    payments::{assert_mint, token_program_for, Payer},
+    state::{MerchProduct, Order, OrderPayment, OrderStatus, TransferFeeMode},
+    utils::{assert_token_account, save_marker},
+};
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct RefundOrder<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(mut)]
+    pub product: Box<Account<'info, MerchProduct>>,
+
+    #[account(mut, has_one = product, has_one = buyer)]
+    pub order: Box<Account<'info, Order>>,
+
+    /// CHECK: checked against order.buyer, receives the refund
+    #[account(mut)]
+    pub buyer: UncheckedAccount<'info>,
+
+    /// CHECK: handled in refund logic
+    #[account(
+        mut,
+        seeds = [
+            CLAIM_MARKER.as_bytes(),
+            order.claim_scope.unwrap_or_default().as_ref(),
+            order.edition.unwrap_or_default().as_ref()
+        ],
+        bump
+    )]
+    pub claim_marker: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: handled in refund logic
+    #[account(
+        mut,
+        seeds = [
+            ALLOWLIST_MARKER.as_bytes(),
+            order.product.as_ref(),
+            order.buyer.as_ref()
+        ],
+        bump
+    )]
+    pub allowlist_marker: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: handled in refund logic
+    #[account(
+        mut,
+        seeds = [
+            PURCHASE_MARKER.as_bytes(),
+            order.product.as_ref(),
+            order.buyer.as_ref()
+        ],
+        bump
+    )]
+    pub purchase_marker: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: handled in refund logic
+    #[account(
+        mut,
+        seeds = [
+            PURCHASE_MARKER.as_bytes(),
+            order.product.as_ref(),
+            order.buyer.as_ref(),
+            &[order.phase_counted.unwrap_or_default()]
+        ],
+        bump
+    )]
+    pub phase_purchase_marker: Option<UncheckedAccount<'info>>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+
+    #[account(address = token::ID)]
+    pub token_program: Program<'info, Token>,
+
+    #[account(address = token_2022::ID)]
+    pub token_2022_program: Option<Program<'info, Token2022>>,
+    // remaining accounts:
+    // in order of order.payments,
+    // for escrowed orders:
+    //   for sol payments: none
+    //   for token payments: mint, order ata, buyer ata
+    // for paid orders:
+    //   for sol payments: none
+    //   for token payments: mint, authority ata, buyer ata
+}
+
+pub fn refund_order<'info>(ctx: Context<'_, '_, '_, 'info, RefundOrder<'info>>) -> Result<()> {
+    let RefundOrder {
+        authority,
+        ref mut product,
+        ref mut order,
+        buyer,
+        claim_marker,
+        allowlist_marker,
+        purchase_marker,
+        phase_purchase_marker,
+        system_program: system_program_account,
+        token_program,
+        token_2022_program,
+        event_authority: _,
+        program: _,
+    } = ctx.accounts;
+
+    if product.authority != authority.key() {
+        msg!(
+            "invalid authority: expected {}, got {}",
+            product.authority,
+            authority.key()
+        );
+        return Err(IncorrectAuthority.into());
+    }
+
+    match order.status {
+        // escrowed payments are returned from the order
+        OrderStatus::Escrowed => {
+            escrow::refund(
+                order,
+                buyer,
+                token_program,
+                token_2022_program.as_ref().map(|program| program.as_ref()),
+                ctx.remaining_accounts,
+            )?;
+            product.process_escrow_closed();
+        }
+        // payments went to the recipients, the seller refunds the buyer
+        OrderStatus::Paid => {
+            let payment_atas = &mut ctx.remaining_accounts.iter();
+            for payment in &order.payments {
+                let OrderPayment { mint, .. } = payment;
+                let refund_amount = payment.total_amount()?;
+
+                msg!("");
+                msg!("refunding {} of {}", refund_amount, mint);
+                // sol refund
+                let (payer, to) = if *mint == Pubkey::default() {
+                    let payer = Payer::System {
+                        from: authority.to_account_info(),
+                        system_program: system_program_account.to_account_info(),
+                    };
+                    (payer, buyer.to_account_info())
+                }
+                // token refund, the buyer bears transfer fees as on the way in
+                else {
+                    let mint_account = next_account_info(payment_atas)?.clone();
+                    let from = next_account_info(payment_atas)?.clone();
+                    let to = next_account_info(payment_atas)?.clone();
+                    assert_mint(&mint_account, mint)?;
+                    assert_token_account(&to, mint, &order.buyer)?;
+                    let payer = Payer::Token {
+                        from,
+                        authority: authority.to_account_info(),
+                        token_program: token_program_for(
+                            &mint_account,
+                            token_program,
+                            token_2022_program.as_ref().map(|program| program.as_ref()),
+                        )?,
+                        mint: mint_account,
+                        transfer_fee_mode: TransferFeeMode::Gross,
+                        signer_seeds: &[],
+                    };
+                    (payer, to)
+                };
+                payer.pay(to, refund_amount)?;
+            }
+        }
+        status => {
+            msg!("order cannot be refunded: status {:?}", status);
+            return Err(InvalidOrderStatus.into());
+        }
+    }
+
+    restore_claims(product, order, claim_marker.as_ref())?;
+    restore_purchases(
+        order,
+        allowlist_marker.as_ref(),
+        purchase_marker.as_ref(),
+        phase_purchase_marker.as_ref(),
+    )?;
+
+    order.status = OrderStatus::Refunded;
+    product.process_refund(order.quantity, order.variant);
+
+    let event = OrderRefunded {
+        order: order.key(),
+        product: product.key(),
+        authority: authority.key(),
+        current_supply: product.current_supply,
+    };
+    emit_cpi!(event);
+
+    Ok(())
+}
+
+/// gives the claims used by the order back to the edition it was claimed with
+pub(crate) fn restore_claims(
+    product: &MerchProduct,
+    order: &Order,
+    claim_marker: Option<&UncheckedAccount>,
+) -> Result<()> {
+    if order.edition.is_none() {
+        return Ok(());
+    }
+
+    let claim_marker = claim_marker.ok_or(MissingClaimMarker)?;
+    if *claim_marker.owner != crate::ID {
+        msg!("claim marker {} not initialized", claim_marker.key());
+        return Err(MissingClaimMarker.into());
+    }
+
+    let claim_count = u32::deserialize(&mut &(**claim_marker.try_borrow_data()?))?;
+    let claim_count = product.process_user_refund(claim_count, order.quantity);
+    save_marker(claim_marker, claim_count)
+}
+
+/// gives the units of the order back to the buyer's allowlist, wallet and phase purchase
+/// markers it was counted in
+pub(crate) fn restore_purchases<'info>(
+    order: &Order,
+    allowlist_marker: Option<&UncheckedAccount<'info>>,
+    purchase_marker: Option<&UncheckedAccount<'info>>,
+    phase_purchase_marker: Option<&UncheckedAccount<'info>>,
+) -> Result<()> {
+    let markers = [
+        (
+            order.allowlist_counted,
+            allowlist_marker,
+            MissingAllowlistMarker,
+        ),
+        (order.wallet_counted, purchase_marker, MissingPurchaseMarker),
+        (
+            order.phase_counted.is_some(),
+            phase_purchase_marker,
+            MissingPurchaseMarker,
+        ),
+    ];
+    for (counted, marker, missing_marker) in markers {
+        if !counted {
+            continue;
+        }
+
+        let marker = marker.ok_or(missing_marker)?;
+        if *marker.owner != crate::ID {
+            msg!("purchase marker {} not initialized", marker.key());
+            return Err(missing_marker.into());
+        }
+
+        let purchase_count = u32::deserialize(&mut &(**marker.try_borrow_data()?))?;
+        save_marker(marker, purchase_count.saturating_sub(order.quantity))?;
+    }
+
+    Ok(())
+}
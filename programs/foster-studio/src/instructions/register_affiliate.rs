@@ -0,0 +1,58 @@
+use anchor_lang::{prelude::*, system_program};
+
+use crate::{constants::AFFILIATE, events::AffiliateUpdated, state::Affiliate};
+
+#[event_cpi]
+#[derive(Accounts)]
+#[instruction(scope: Pubkey)]
+pub struct RegisterAffiliate<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub wallet: Signer<'info>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = Affiliate::SPACE,
+        seeds = [AFFILIATE.as_bytes(), scope.as_ref(), wallet.key().as_ref()],
+        bump
+    )]
+    pub affiliate: Box<Account<'info, Affiliate>>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+/// registers `wallet` as an affiliate of `scope`, a product or a seller.
+/// the affiliate is active but can only be paid once approved by the seller
+pub fn register_affiliate(ctx: Context<RegisterAffiliate>, scope: Pubkey) -> Result<()> {
+    let RegisterAffiliate {
+        payer: _,
+        wallet,
+        affiliate,
+        system_program: _,
+        event_authority: _,
+        program: _,
+    } = ctx.accounts;
+
+    affiliate.set_inner(Affiliate {
+        wallet: wallet.key(),
+        scope,
+        commission_bps: None,
+        active: true,
+        approved: false,
+        bump: ctx.bumps.affiliate,
+    });
+
+    emit_cpi!(AffiliateUpdated {
+        affiliate: affiliate.key(),
+        wallet: affiliate.wallet,
+        scope: affiliate.scope,
+        commission_bps: affiliate.commission_bps,
+        active: affiliate.active,
+        approved: affiliate.approved,
+    });
+
+    Ok(())
+}
@@ -0,0 +1,217 @@
+use anchor_lang::{prelude::*, solana_program::account_info::next_account_info};
+use anchor_spl::{
+    token::{self, Token},
+    token_2022::{self, Token2022},
+};
+
+use crate::{
+    constants::PRODUCT_STATS,
+    errors::*,
+    escrow,
+    events::OrderReleased,
+    payments::assert_fee_recipient,
+    state::{MerchProduct, Order, OrderPayment, OrderStatus, SalesStats},
+    utils::{assert_token_account, assert_token_account_mint},
+};
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct ReleaseOrder<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(mut)]
+    pub product: Box<Account<'info, MerchProduct>>,
+
+    #[account(mut, has_one = product, has_one = buyer)]
+    pub order: Box<Account<'info, Order>>,
+
+    /// CHECK: checked against order.buyer, receives the rent of the escrow vaults
+    #[account(mut)]
+    pub buyer: UncheckedAccount<'info>,
+
+    /// CHECK: checked against order.referrer
+    #[account(mut)]
+    pub referrer: Option<UncheckedAccount<'info>>,
+
+    /// required to price auction rebates at the last sale
+    #[account(seeds = [PRODUCT_STATS.as_bytes(), product.key().as_ref()], bump)]
+    pub product_stats: Option<Box<Account<'info, SalesStats>>>,
+
+    #[account(address = token::ID)]
+    pub token_program: Program<'info, Token>,
+
+    #[account(address = token_2022::ID)]
+    pub token_2022_program: Option<Program<'info, Token2022>>,
+    // remaining accounts:
+    // in order of order.payments,
+    // for sol payments: recipient
+    // for token payments: mint, order ata, to ata, [referrer ata, if there is a referrer]
+    // followed by [fee recipient, if payment.platform_fee > 0]
+    // and [buyer ata, for token payments if product.auction_rebate]
+}
+
+pub fn release_order<'info>(ctx: Context<'_, '_, '_, 'info, ReleaseOrder<'info>>) -> Result<()> {
+    let ReleaseOrder {
+        authority,
+        product,
+        order,
+        buyer,
+        referrer,
+        product_stats,
+        token_program,
+        token_2022_program,
+        event_authority: _,
+        program: _,
+    } = &ctx.accounts;
+
+    // the seller marks the order fulfilled, or the buyer confirms delivery
+    if authority.key() != product.authority && authority.key() != order.buyer {
+        msg!(
+            "invalid authority: expected {} or {}, got {}",
+            product.authority,
+            order.buyer,
+            authority.key()
+        );
+        return Err(IncorrectAuthority.into());
+    }
+    order.assert_status(OrderStatus::Escrowed)?;
+
+    // rebates are known once the auction is over, at the price of the last sale
+    let rebates = if product.auction_rebate {
+        let now = Clock::get()?.unix_timestamp;
+        let sale_end_at = product.sale_end_at.unwrap_or_default();
+        if now <= sale_end_at {
+            msg!("auction ends at {}, now = {}", sale_end_at, now);
+            return Err(AuctionNotEnded.into());
+        }
+        let product_stats = product_stats.as_ref().ok_or_else(|| {
+            msg!("missing product stats");
+            AccountMismatch
+        })?;
+        product.auction_rebates(order, product_stats.last_sale_at)?
+    } else {
+        vec![0; order.payments.len()]
+    };
+
+    let referrer = match order.referrer {
+        Some(expected_referrer) => {
+            let referrer = referrer.as_ref().ok_or_else(|| {
+                msg!("missing referrer: expected {}", expected_referrer);
+                AccountMismatch
+            })?;
+            if referrer.key() != expected_referrer {
+                msg!(
+                    "invalid referrer: expected {}, got {}",
+                    expected_referrer,
+                    referrer.key()
+                );
+                return Err(AccountMismatch.into());
+            }
+            Some(referrer)
+        }
+        None => None,
+    };
+
+    // release payments
+    escrow::with_order_signer(order, |signer_seeds| {
+        let payment_atas = &mut ctx.remaining_accounts.iter();
+        for (payment, rebate) in order.payments.iter().zip(rebates) {
+            let OrderPayment {
+                mint,
+                recipient,
+                amount,
+                referrer_amount,
+                platform_fee,
+                fee_recipient,
+                ..
+            } = payment;
+
+            msg!("");
+            msg!("releasing {} of {}", amount, mint);
+            let payer = escrow::vault_payer(
+                order,
+                mint,
+                payment_atas,
+                token_program,
+                token_2022_program.as_ref().map(|program| program.as_ref()),
+                signer_seeds,
+            )?;
+
+            // paid out of the vault in order: recipient, referrer, platform fee and rebate
+            let mut amounts = vec![amount.checked_sub(rebate).ok_or(NumericalOverflow)?];
+            if referrer.is_some() {
+                amounts.push(*referrer_amount);
+            }
+            if *platform_fee > 0 {
+                amounts.push(*platform_fee);
+            }
+            if product.auction_rebate {
+                amounts.push(rebate);
+            }
+            let mut transfers = escrow::vault_transfers(&payer, payment, &amounts)?.into_iter();
+
+            let to = next_account_info(payment_atas)?.clone();
+            if *recipient != to.key() {
+                msg!(
+                    "invalid recipient: expected {}, got {}",
+                    recipient,
+                    to.key()
+                );
+                return Err(AccountMismatch.into());
+            }
+            if *mint != Pubkey::default() {
+                assert_token_account_mint(&to, mint)?;
+            }
+            payer.pay(to, transfers.next().unwrap_or_default())?;
+
+            if let Some(referrer) = referrer {
+                msg!("referral payment");
+                let referrer_account = if *mint == Pubkey::default() {
+                    referrer.to_account_info()
+                } else {
+                    let referrer_ata = next_account_info(payment_atas)?.clone();
+                    assert_token_account(&referrer_ata, mint, &referrer.key())?;
+                    referrer_ata
+                };
+                payer.pay(referrer_account, transfers.next().unwrap_or_default())?;
+            }
+
+            if *platform_fee > 0 {
+                let fee_account = next_account_info(payment_atas)?.clone();
+                assert_fee_recipient(&fee_account, fee_recipient, mint)?;
+                msg!("platform fee");
+                payer.pay(fee_account, transfers.next().unwrap_or_default())?;
+            }
+
+            if product.auction_rebate {
+                let buyer_account = if *mint == Pubkey::default() {
+                    buyer.to_account_info()
+                } else {
+                    let buyer_ata = next_account_info(payment_atas)?.clone();
+                    assert_token_account(&buyer_ata, mint, &order.buyer)?;
+                    buyer_ata
+                };
+                msg!("auction rebate");
+                payer.pay(buyer_account, transfers.next().unwrap_or_default())?;
+            }
+
+            escrow::close_vault(&payer, buyer.to_account_info())?;
+        }
+
+        Ok(())
+    })?;
+
+    let order_key = order.key();
+    let product_key = product.key();
+    let authority_key = authority.key();
+    ctx.accounts.order.status = OrderStatus::Released;
+    ctx.accounts.product.process_escrow_closed();
+
+    emit_cpi!(OrderReleased {
+        order: order_key,
+        product: product_key,
+        authority: authority_key,
+    });
+
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use anchor_lang::prelude::*;
+
+use crate::{
+    constants::BASIS_POINTS_DENOMINATOR,
+    errors::*,
+    events::AffiliateUpdated,
+    state::{Affiliate, AffiliateConfig, MerchProduct},
+};
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct UpdateAffiliate<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(mut)]
+    pub affiliate: Box<Account<'info, Affiliate>>,
+
+    /// required for affiliates registered with a product
+    pub product: Option<Box<Account<'info, MerchProduct>>>,
+}
+
+/// the seller sets the commission rate and approval,
+/// the seller or the affiliate can (de)activate the affiliate
+pub fn update_affiliate(
+    ctx: Context<UpdateAffiliate>,
+    affiliate_config: AffiliateConfig,
+) -> Result<()> {
+    let UpdateAffiliate {
+        authority,
+        affiliate,
+        product,
+        event_authority: _,
+        program: _,
+    } = ctx.accounts;
+
+    let seller = match product {
+        Some(product) if product.key() == affiliate.scope => product.authority,
+        _ => affiliate.scope,
+    };
+    let AffiliateConfig {
+        commission_bps,
+        active,
+        approved,
+    } = affiliate_config;
+
+    if authority.key() == seller {
+        if let Some(commission_bps) = commission_bps {
+            if commission_bps.unwrap_or_default() as u64 > BASIS_POINTS_DENOMINATOR {
+                msg!("invalid commission: {:?} bps", commission_bps);
+                return Err(InvalidCommission.into());
+            }
+            affiliate.commission_bps = commission_bps;
+        }
+        if let Some(approved) = approved {
+            affiliate.approved = approved;
+        }
+    } else if authority.key() == affiliate.wallet {
+        if commission_bps.is_some() || approved.is_some() {
+            msg!("only the seller can change the commission and approval");
+            return Err(IncorrectAuthority.into());
+        }
+    } else {
+        msg!(
+            "invalid authority: expected {} or {}, got {}",
+            seller,
+            affiliate.wallet,
+            authority.key()
+        );
+        return Err(IncorrectAuthority.into());
+    }
+    if let Some(active) = active {
+        affiliate.active = active;
+    }
+
+    emit_cpi!(AffiliateUpdated {
+        affiliate: affiliate.key(),
+        wallet: affiliate.wallet,
+        scope: affiliate.scope,
+        commission_bps: affiliate.commission_bps,
+        active: affiliate.active,
+        approved: affiliate.approved,
+    });
+
+    Ok(())
+}
@@ -0,0 +1,65 @@
+use anchor_lang::{prelude::*, system_program};
+
+use crate::{
+    constants::CONFIG,
+    errors::*,
+    events::ConfigUpdated,
+    state::{Config, ConfigArgs},
+};
+
+#[event_cpi]
+#[derive(Accounts)]
+#[instruction(args: ConfigArgs)]
+pub struct UpdateConfig<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [CONFIG.as_bytes()],
+        bump = config.bump,
+        realloc = Config::space(
+            args.fee_recipients
+                .as_ref()
+                .map_or(config.fee_recipients.len(), Vec::len)
+        ),
+        realloc::payer = admin,
+        realloc::zero = false
+    )]
+    pub config: Box<Account<'info, Config>>,
+
+    #[account(address = system_program::ID)]
+    pub system_program: Program<'info, System>,
+}
+
+pub fn update_config(ctx: Context<UpdateConfig>, args: ConfigArgs) -> Result<()> {
+    let UpdateConfig {
+        admin,
+        config,
+        system_program: _,
+        event_authority: _,
+        program: _,
+    } = ctx.accounts;
+    if config.admin != admin.key() {
+        msg!(
+            "invalid admin: expected {}, got {}",
+            config.admin,
+            admin.key()
+        );
+        return Err(IncorrectAuthority.into());
+    }
+
+    let new_config = args.update_config(config);
+    new_config.validate()?;
+    config.set_inner(new_config);
+
+    emit_cpi!(ConfigUpdated {
+        config: config.key(),
+        admin: config.admin,
+        platform_fee_bps: config.platform_fee_bps,
+        fee_recipients: config.fee_recipients.clone(),
+        paused: config.paused,
+    });
+
+    Ok(())
+}
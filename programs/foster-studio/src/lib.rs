@@ -2,12 +2,16 @@ use anchor_lang::prelude::*;
 
 pub mod constants;
 pub mod errors;
+pub mod escrow;
+pub mod events;
 pub mod instructions;
 pub mod mpl_token_metadata;
+pub mod payments;
 pub mod state;
+pub mod utils;
 
 use crate::instructions::*;
-use state::MerchProductConfig;
+use state::{AffiliateConfig, ConfigArgs, MerchProductConfig};
 
 declare_id!("fosfNb548Xe2UbMEVcrmsZWWVdxMYAuoyrxQxBvYHJj");
 
@@ -22,11 +26,49 @@ pub mod foster_studio {
         instructions::configure_product(ctx, product_config)
     }
 
-    pub fn buy_product<'info>(ctx: Context<'_, '_, '_, 'info, BuyProduct<'info>>) -> Result<()> {
-        instructions::buy_product(ctx)
+    pub fn buy_product<'info>(
+        ctx: Context<'_, '_, '_, 'info, BuyProduct<'info>>,
+        args: BuyProductArgs,
+    ) -> Result<()> {
+        instructions::buy_product(ctx, args)
     }
 
     pub fn delete_product(ctx: Context<DeleteProduct>) -> Result<()> {
         instructions::delete_product(ctx)
     }
+
+    pub fn release_order<'info>(
+        ctx: Context<'_, '_, '_, 'info, ReleaseOrder<'info>>,
+    ) -> Result<()> {
+        instructions::release_order(ctx)
+    }
+
+    pub fn refund_order<'info>(ctx: Context<'_, '_, '_, 'info, RefundOrder<'info>>) -> Result<()> {
+        instructions::refund_order(ctx)
+    }
+
+    pub fn claim_order_refund<'info>(
+        ctx: Context<'_, '_, '_, 'info, ClaimOrderRefund<'info>>,
+    ) -> Result<()> {
+        instructions::claim_order_refund(ctx)
+    }
+
+    pub fn initialize_config(ctx: Context<InitializeConfig>, args: ConfigArgs) -> Result<()> {
+        instructions::initialize_config(ctx, args)
+    }
+
+    pub fn update_config(ctx: Context<UpdateConfig>, args: ConfigArgs) -> Result<()> {
+        instructions::update_config(ctx, args)
+    }
+
+    pub fn register_affiliate(ctx: Context<RegisterAffiliate>, scope: Pubkey) -> Result<()> {
+        instructions::register_affiliate(ctx, scope)
+    }
+
+    pub fn update_affiliate(
+        ctx: Context<UpdateAffiliate>,
+        affiliate_config: AffiliateConfig,
+    ) -> Result<()> {
+        instructions::update_affiliate(ctx, affiliate_config)
+    }
 }
@@ -0,0 +1,197 @@
+use anchor_lang::{prelude::*, system_program};
+use anchor_spl::{
+    token,
+    token_2022::{
+        self,
+        spl_token_2022::{
+            extension::{
+                transfer_fee::{TransferFeeAmount, TransferFeeConfig},
+                BaseStateWithExtensions, StateWithExtensions,
+            },
+            state::{Account as TokenAccountState, Mint as MintState},
+        },
+    },
+    token_interface::{self, Mint},
+};
+
+use crate::{errors::*, state::TransferFeeMode, utils::assert_token_account_mint};
+
+/// source of a payment
+pub enum Payer<'a, 'info> {
+    /// lamports from a system account signer
+    System {
+        from: AccountInfo<'info>,
+        system_program: AccountInfo<'info>,
+    },
+    /// lamports held by an account owned by this program
+    Program { from: AccountInfo<'info> },
+    /// tokens from a token account, of either token program
+    Token {
+        from: AccountInfo<'info>,
+        authority: AccountInfo<'info>,
+        mint: AccountInfo<'info>,
+        token_program: AccountInfo<'info>,
+        transfer_fee_mode: TransferFeeMode,
+        signer_seeds: &'a [&'a [&'a [u8]]],
+    },
+}
+
+impl<'a, 'info> Payer<'a, 'info> {
+    pub fn pay(&self, to: AccountInfo<'info>, amount: u64) -> Result<()> {
+        match self {
+            Self::System {
+                from,
+                system_program,
+            } => {
+                msg!(
+                    "from {} to {} for {} lamports",
+                    from.key(),
+                    to.key(),
+                    amount
+                );
+                system_program::transfer(
+                    CpiContext::new(
+                        system_program.clone(),
+                        system_program::Transfer {
+                            from: from.clone(),
+                            to,
+                        },
+                    ),
+                    amount,
+                )
+            }
+            Self::Program { from } => {
+                msg!(
+                    "from {} to {} for {} lamports",
+                    from.key(),
+                    to.key(),
+                    amount
+                );
+                from.sub_lamports(amount)?;
+                to.add_lamports(amount)?;
+                Ok(())
+            }
+            Self::Token {
+                from,
+                authority,
+                mint,
+                token_program,
+                transfer_fee_mode,
+                signer_seeds,
+            } => {
+                let transfer_amount = transfer_fee_mode.transfer_amount(mint, amount)?;
+                msg!(
+                    "from {} to {} for {} {}",
+                    from.key(),
+                    to.key(),
+                    transfer_amount,
+                    mint.key()
+                );
+                let decimals = Mint::try_deserialize(&mut &**mint.try_borrow_data()?)?.decimals;
+                token_interface::transfer_checked(
+                    CpiContext::new_with_signer(
+                        token_program.clone(),
+                        token_interface::TransferChecked {
+                            from: from.clone(),
+                            mint: mint.clone(),
+                            to,
+                            authority: authority.clone(),
+                        },
+                        signer_seeds,
+                    ),
+                    transfer_amount,
+                    decimals,
+                )
+            }
+        }
+    }
+}
+
+pub fn assert_mint(mint: &AccountInfo, expected_mint: &Pubkey) -> Result<()> {
+    if mint.key() != *expected_mint {
+        msg!(
+            "invalid mint: expected {}, got {}",
+            expected_mint,
+            mint.key()
+        );
+        return Err(AccountMismatch.into());
+    }
+
+    Ok(())
+}
+
+pub fn assert_fee_recipient(
+    fee_account: &AccountInfo,
+    fee_recipient: &Pubkey,
+    mint: &Pubkey,
+) -> Result<()> {
+    if fee_account.key() != *fee_recipient {
+        msg!(
+            "invalid fee recipient: expected {}, got {}",
+            fee_recipient,
+            fee_account.key()
+        );
+        return Err(AccountMismatch.into());
+    }
+    if *mint != Pubkey::default() {
+        assert_token_account_mint(fee_account, mint)?;
+    }
+
+    Ok(())
+}
+
+/// token program owning `mint`, token-2022 mints require the token-2022 program
+pub fn token_program_for<'info>(
+    mint: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+    token_2022_program: Option<&AccountInfo<'info>>,
+) -> Result<AccountInfo<'info>> {
+    if *mint.owner == token::ID {
+        Ok(token_program.clone())
+    } else if *mint.owner == token_2022::ID {
+        token_2022_program.cloned().ok_or_else(|| {
+            msg!("missing token-2022 program for mint {}", mint.key());
+            MissingTokenProgram.into()
+        })
+    } else {
+        msg!(
+            "invalid mint owner for {}: {} is not a token program",
+            mint.key(),
+            mint.owner
+        );
+        Err(AccountMismatch.into())
+    }
+}
+
+/// fee to add to a transfer of `mint` so that the recipient receives `amount`
+pub fn inverse_transfer_fee(mint: &AccountInfo, amount: u64) -> Result<u64> {
+    if *mint.owner != token_2022::ID {
+        return Ok(0);
+    }
+
+    let mint_data = mint.try_borrow_data()?;
+    let mint_state = StateWithExtensions::<MintState>::unpack(&mint_data)?;
+    let Ok(transfer_fee_config) = mint_state.get_extension::<TransferFeeConfig>() else {
+        return Ok(0);
+    };
+
+    Ok(transfer_fee_config
+        .calculate_inverse_epoch_fee(Clock::get()?.epoch, amount)
+        .ok_or(NumericalOverflow)?)
+}
+
+/// transfer fees withheld in `token_account`, token-2022 cannot close it until they are harvested
+pub fn withheld_transfer_fees(token_account: &AccountInfo) -> Result<u64> {
+    if *token_account.owner != token_2022::ID {
+        return Ok(0);
+    }
+
+    let token_account_data = token_account.try_borrow_data()?;
+    let token_account_state =
+        StateWithExtensions::<TokenAccountState>::unpack(&token_account_data)?;
+    Ok(token_account_state
+        .get_extension::<TransferFeeAmount>()
+        .map_or(0, |transfer_fee_amount| {
+            u64::from(transfer_fee_amount.withheld_amount)
+        }))
+}
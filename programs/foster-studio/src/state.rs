@@ -1,7 +1,14 @@
-use anchor_lang::{prelude::*, AnchorDeserialize, AnchorSerialize, Discriminator};
+use anchor_lang::{
+    prelude::*, solana_program::hash::hashv, AnchorDeserialize, AnchorSerialize, Discriminator,
+};
 use anchor_spl::metadata::mpl_token_metadata::accounts::MasterEdition;
 
-use crate::errors::*;
+use crate::{
+    constants::{BASIS_POINTS_DENOMINATOR, MAX_ESCROW_TIMEOUT},
+    errors::*,
+    payments::inverse_transfer_fee,
+    utils::verify_merkle_proof,
+};
 
 #[account]
 pub struct MerchProduct {
@@ -14,15 +21,31 @@ pub struct MerchProduct {
     pub category: String,
     pub current_supply: u64,
     pub max_supply: MaxSupply,
+    pub variants: Vec<ProductVariant>,
 
     pub sale_start_at: Option<i64>,
     pub sale_end_at: Option<i64>,
+    pub paused: bool,
+    pub phases: Vec<SalePhase>,
 
     pub linked_master_nft: Option<Pubkey>,
+    pub linked_collection: Option<Pubkey>,
     pub claims_per_edition: u32,
+    pub claim_pool: Option<Pubkey>,
+    pub claim_pool_scope: Option<Pubkey>,
+    pub max_per_wallet: u32,
+
+    pub allowlist_root: Option<[u8; 32]>,
 
     pub payments: Vec<PaymentConfig>,
     pub affiliate_commission_bps: u16,
+    pub commission_mode: CommissionMode,
+
+    pub escrow: Option<EscrowConfig>,
+    pub auction_rebate: bool,
+
+    pub order_count: u64,
+    pub open_escrows: u64,
 }
 
 impl MerchProduct {
@@ -30,11 +53,373 @@ impl MerchProduct {
         Ok([&Self::DISCRIMINATOR, self.try_to_vec()?.as_slice()].concat())
     }
 
-    pub fn assert_supply(&self, master_edition: Option<&MasterEdition>) -> Result<()> {
+    pub fn validate(&self) -> Result<()> {
+        for payment in &self.payments {
+            payment.validate()?;
+
+            if payment.auction.is_some() && self.sale_start_at.is_none() {
+                msg!("auction for {} requires a sale start", payment.tag);
+                return Err(InvalidAuction.into());
+            }
+        }
+
+        if self.affiliate_commission_bps as u64 > BASIS_POINTS_DENOMINATOR {
+            msg!("invalid commission: {} bps", self.affiliate_commission_bps);
+            return Err(InvalidCommission.into());
+        }
+
+        if self.variants.len() > u8::MAX as usize {
+            msg!("too many variants: {}", self.variants.len());
+            return Err(InvalidVariant.into());
+        }
+        for variant in &self.variants {
+            if let Some(amounts) = &variant.amounts {
+                if amounts.len() != self.payments.len() {
+                    msg!(
+                        "invalid variant {}: {} amounts for {} payments",
+                        variant.label,
+                        amounts.len(),
+                        self.payments.len()
+                    );
+                    return Err(InvalidVariant.into());
+                }
+                // auctions cannot start below their floor
+                for (amount, payment) in amounts.iter().zip(&self.payments) {
+                    if let Some(auction) = &payment.auction {
+                        if *amount < auction.floor_amount {
+                            msg!(
+                                "invalid variant {}: amount {} below the auction floor {} of {}",
+                                variant.label,
+                                amount,
+                                auction.floor_amount,
+                                payment.tag
+                            );
+                            return Err(InvalidVariant.into());
+                        }
+                    }
+                }
+            }
+        }
+
+        if self.phases.len() > u8::MAX as usize {
+            msg!("too many sale phases: {}", self.phases.len());
+            return Err(InvalidSalePhase.into());
+        }
+        for phase in &self.phases {
+            let gate_configured = match phase.gate {
+                PhaseGate::Public => true,
+                PhaseGate::Holders => {
+                    self.linked_master_nft.is_some() || self.linked_collection.is_some()
+                }
+                PhaseGate::Allowlist => self.allowlist_root.is_some(),
+            };
+            let window_valid = match (phase.start_at, phase.end_at) {
+                (Some(start_at), Some(end_at)) => start_at <= end_at,
+                _ => true,
+            };
+            let price_valid =
+                phase.price_bps > 0 && phase.price_bps as u64 <= BASIS_POINTS_DENOMINATOR;
+            if !gate_configured || !window_valid || !price_valid {
+                msg!(
+                    "invalid sale phase {}: gate {:?} configured = {}, valid window = {}, \
+                     price {} bps",
+                    phase.name,
+                    phase.gate,
+                    gate_configured,
+                    window_valid,
+                    phase.price_bps
+                );
+                return Err(InvalidSalePhase.into());
+            }
+        }
+
+        if let Some(EscrowConfig { timeout }) = self.escrow {
+            if timeout <= 0 || timeout > MAX_ESCROW_TIMEOUT {
+                msg!(
+                    "invalid escrow timeout: {}, expected 1 to {} seconds",
+                    timeout,
+                    MAX_ESCROW_TIMEOUT
+                );
+                return Err(InvalidEscrow.into());
+            }
+        }
+
+        // rebates are held in escrow until the auction is over
+        if self.auction_rebate && (self.escrow.is_none() || self.sale_end_at.is_none()) {
+            msg!("auction rebates require escrow and a sale end");
+            return Err(InvalidAuction.into());
+        }
+        if self.auction_rebate
+            && self
+                .payments
+                .iter()
+                .all(|payment| payment.auction.is_none())
+        {
+            msg!("auction rebates require an auctioned payment");
+            return Err(InvalidAuction.into());
+        }
+
+        Ok(())
+    }
+
+    /// number of order payments recorded for `option`, one per payment recipient
+    pub fn payment_count(&self, option: u8) -> usize {
+        self.payments
+            .iter()
+            .filter(|payment| payment.option == option)
+            .map(|payment| payment.splits.len().max(1))
+            .sum()
+    }
+
+    /// payments charged for `option`, all of them have to be paid.
+    /// amounts are those of `variant` if it overrides them
+    pub fn payment_option(&self, option: u8, variant: Option<u8>) -> Result<Vec<PaymentConfig>> {
+        let amounts = match variant {
+            Some(variant) => self.variant(variant)?.amounts.as_ref(),
+            None => None,
+        };
+        let payments: Vec<_> = self
+            .payments
+            .iter()
+            .enumerate()
+            .filter(|(_, payment)| payment.option == option)
+            .map(|(i, payment)| PaymentConfig {
+                amount: amounts.map_or(payment.amount, |amounts| amounts[i]),
+                ..payment.clone()
+            })
+            .collect();
+        if payments.is_empty() && !self.payments.is_empty() {
+            msg!("invalid payment option {}", option);
+            return Err(InvalidPaymentOption.into());
+        }
+
+        Ok(payments)
+    }
+
+    /// amounts given back to the buyer of `order` once the auction cleared at the
+    /// price of `cleared_at`, one per order payment.
+    /// rebates are deducted from the seller's share of each payment
+    pub fn auction_rebates(&self, order: &Order, cleared_at: i64) -> Result<Vec<u64>> {
+        if !self.auction_rebate {
+            return Ok(vec![0; order.payments.len()]);
+        }
+
+        let mut rebates = Vec::with_capacity(order.payments.len());
+        let mut order_payments = order.payments.iter();
+        for payment in self.payment_option(order.payment_option, order.variant)? {
+            let order_payments: Vec<_> = order_payments
+                .by_ref()
+                .take(payment.splits.len().max(1))
+                .collect();
+            let Some(first) = order_payments.first() else {
+                break;
+            };
+            if first.mint != payment.mint {
+                msg!(
+                    "payments changed since the order: expected {}, got {}",
+                    payment.mint,
+                    first.mint
+                );
+                return Err(InvalidAuction.into());
+            }
+
+            // only auctioned payments are priced again
+            if payment.auction.is_none() {
+                rebates.extend(vec![0; order_payments.len()]);
+                continue;
+            }
+
+            let clearing_price = payment.price(self.sale_start_at, cleared_at);
+            let seller_amount = order_payments
+                .iter()
+                .map(|order_payment| order_payment.amount)
+                .sum::<u64>();
+            let rebate = first
+                .unit_price
+                .saturating_sub(clearing_price)
+                .checked_mul(order.quantity as u64)
+                .ok_or(NumericalOverflow)?
+                .min(seller_amount);
+            msg!(
+                "clearing price of {}: {}, rebate = {}",
+                payment.tag,
+                clearing_price,
+                rebate
+            );
+
+            // shared in proportion to the recipients' amounts, remainder on the first
+            let mut shares = order_payments
+                .iter()
+                .map(|order_payment| {
+                    (order_payment.amount as u128 * rebate as u128 / seller_amount.max(1) as u128)
+                        as u64
+                })
+                .collect::<Vec<_>>();
+            shares[0] += rebate - shares.iter().sum::<u64>();
+            rebates.extend(shares);
+        }
+        if rebates.len() != order.payments.len() {
+            msg!("payments changed since the order");
+            return Err(InvalidAuction.into());
+        }
+
+        Ok(rebates)
+    }
+
+    /// sha256 of the borsh serialized payments followed by the borsh serialized amounts of every
+    /// variant, for buyers to commit to the prices
+    pub fn payments_hash(&self) -> Result<[u8; 32]> {
+        let variant_amounts = self
+            .variants
+            .iter()
+            .map(|variant| variant.amounts.clone())
+            .collect::<Vec<_>>();
+
+        Ok(hashv(&[&self.payments.try_to_vec()?, &variant_amounts.try_to_vec()?]).to_bytes())
+    }
+
+    /// checks the terms the buyer signed against the purchase: `commission_bps` is the rate
+    /// charged, the affiliate's or 0 without referrer, `price_bps` the active sale phase's price
+    pub fn assert_terms(
+        &self,
+        payments_hash: &[u8; 32],
+        expected_commission_bps: u16,
+        commission_bps: u16,
+        expected_price_bps: u16,
+        price_bps: u16,
+    ) -> Result<()> {
+        if self.payments_hash()? != *payments_hash {
+            msg!("payments changed since the buyer signed");
+            return Err(TermsChanged.into());
+        }
+        if price_bps != expected_price_bps {
+            msg!(
+                "sale phase price changed: expected {} bps, got {} bps",
+                expected_price_bps,
+                price_bps
+            );
+            return Err(TermsChanged.into());
+        }
+        if commission_bps != expected_commission_bps {
+            msg!(
+                "affiliate commission changed: expected {} bps, got {} bps",
+                expected_commission_bps,
+                commission_bps
+            );
+            return Err(TermsChanged.into());
+        }
+
+        Ok(())
+    }
+
+    /// referrers cannot be the buyer, the seller or a payment recipient
+    pub fn assert_valid_referrer(&self, referrer: &Pubkey, buyer: &Pubkey) -> Result<()> {
+        if referrer == buyer {
+            msg!("buyer {} cannot refer themselves", buyer);
+            return Err(InvalidReferrer.into());
+        }
+        if *referrer == self.authority {
+            msg!("seller {} cannot refer their own product", referrer);
+            return Err(InvalidReferrer.into());
+        }
+        self.assert_not_recipient(referrer)
+    }
+
+    /// `account` does not receive any of the product's payments
+    pub fn assert_not_recipient(&self, account: &Pubkey) -> Result<()> {
+        let is_recipient = self.payments.iter().any(|payment| {
+            payment.recipient == *account
+                || payment
+                    .splits
+                    .iter()
+                    .any(|split| split.recipient == *account)
+        });
+        if is_recipient {
+            msg!("referrer account {} is a payment recipient", account);
+            return Err(InvalidReferrer.into());
+        }
+
+        Ok(())
+    }
+
+    /// orders refer to variants by index, so variants with units sold cannot be dropped or moved
+    pub fn assert_variants_kept(&self, old: &MerchProduct) -> Result<()> {
+        for (i, old_variant) in old.variants.iter().enumerate() {
+            if old_variant.current_supply == 0 {
+                continue;
+            }
+            let kept = matches!(
+                self.variants.get(i),
+                Some(variant) if variant.label == old_variant.label
+            );
+            if !kept {
+                msg!(
+                    "variant {} ({}) has {} units sold, it cannot be dropped or moved",
+                    i,
+                    old_variant.label,
+                    old_variant.current_supply
+                );
+                return Err(InvalidVariant.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn variant(&self, index: u8) -> Result<&ProductVariant> {
+        self.variants.get(index as usize).ok_or_else(|| {
+            msg!(
+                "invalid variant {}, product has {}",
+                index,
+                self.variants.len()
+            );
+            InvalidVariant.into()
+        })
+    }
+
+    /// products with variants must be bought in one of them, products without cannot
+    pub fn assert_variant(&self, variant: Option<u8>) -> Result<()> {
+        match variant {
+            Some(variant) => self.variant(variant).map(|_| ()),
+            None if self.variants.is_empty() => Ok(()),
+            None => {
+                msg!("missing variant");
+                Err(InvalidVariant.into())
+            }
+        }
+    }
+
+    pub fn assert_supply(
+        &self,
+        master_edition: Option<&MasterEdition>,
+        quantity: u32,
+        variant: Option<u8>,
+    ) -> Result<()> {
         if self.linked_master_nft.is_some() && master_edition.is_none() {
             return Err(MissingMasterEdition.into());
         }
 
+        // the variant's own cap, on top of the product's total
+        if let Some(variant) = variant {
+            let variant = self.variant(variant)?;
+            let new_supply = variant
+                .current_supply
+                .checked_add(quantity as u64)
+                .ok_or(NumericalOverflow)?;
+            if let Some(max_supply) = variant.max_supply {
+                if new_supply > max_supply {
+                    msg!(
+                        "no more supply of {}: max supply = {}, current supply = {}, quantity = {}",
+                        variant.label,
+                        max_supply,
+                        variant.current_supply,
+                        quantity
+                    );
+                    return Err(NoMoreSupply.into());
+                }
+            }
+        }
+
         let max_supply = match self.max_supply {
             MaxSupply::None => return Ok(()),
             MaxSupply::Some(count) => count,
@@ -47,15 +432,31 @@ impl MerchProduct {
             }
         };
 
-        if self.current_supply < max_supply {
+        let new_supply = self
+            .current_supply
+            .checked_add(quantity as u64)
+            .ok_or(NumericalOverflow)?;
+        if new_supply <= max_supply {
             Ok(())
         } else {
-            msg!("no more supply: max supply = {}", max_supply);
+            msg!(
+                "no more supply: max supply = {}, current supply = {}, quantity = {}",
+                max_supply,
+                self.current_supply,
+                quantity
+            );
             Err(NoMoreSupply.into())
         }
     }
 
-    pub fn assert_is_live(&self) -> Result<()> {
+    /// checks the product can be bought now, returning the active sale phase and its index
+    /// if the sale has phases
+    pub fn assert_is_live(&self) -> Result<Option<(u8, SalePhase)>> {
+        if self.paused {
+            msg!("product {} is paused", self.id);
+            return Err(ProductPaused.into());
+        }
+
         let now = Clock::get()?.unix_timestamp;
         if let Some(start) = self.sale_start_at {
             if now < start {
@@ -70,15 +471,117 @@ impl MerchProduct {
             }
         }
 
+        if self.phases.is_empty() {
+            return Ok(None);
+        }
+        // the first phase whose window contains now
+        let (index, phase) = self
+            .phases
+            .iter()
+            .enumerate()
+            .find(|(_, phase)| phase.is_active(now))
+            .ok_or_else(|| {
+                msg!("no active sale phase, now = {}", now);
+                NoActivePhase
+            })?;
+        msg!("sale phase: {}", phase.name);
+
+        Ok(Some((index as u8, phase.clone())))
+    }
+
+    pub fn assert_allowlisted(
+        &self,
+        buyer: &Pubkey,
+        allocation: u32,
+        proof: &[[u8; 32]],
+    ) -> Result<()> {
+        let Some(allowlist_root) = self.allowlist_root else {
+            return Ok(());
+        };
+
+        let leaf = [buyer.as_ref(), &allocation.to_le_bytes()].concat();
+        if !verify_merkle_proof(&allowlist_root, &leaf, proof) {
+            msg!("buyer {} not on allowlist", buyer);
+            return Err(NotAllowlisted.into());
+        }
+
         Ok(())
     }
 
-    pub fn process_claim(&mut self) {
-        self.current_supply += 1;
+    pub fn process_allowlist_claim(
+        &self,
+        purchase_count: u32,
+        allocation: u32,
+        quantity: u32,
+    ) -> Result<u32> {
+        let new_purchase_count = purchase_count
+            .checked_add(quantity)
+            .ok_or(NumericalOverflow)?;
+        if allocation > 0 && new_purchase_count > allocation {
+            msg!(
+                "allowlist allocation reached: max {}, purchased {}",
+                allocation,
+                purchase_count
+            );
+            return Err(NoMoreAllocation.into());
+        }
+
+        Ok(new_purchase_count)
+    }
+
+    pub fn process_wallet_purchase(&self, purchase_count: u32, quantity: u32) -> Result<u32> {
+        let new_purchase_count = purchase_count
+            .checked_add(quantity)
+            .ok_or(NumericalOverflow)?;
+        if self.max_per_wallet > 0 && new_purchase_count > self.max_per_wallet {
+            msg!(
+                "wallet purchase limit reached: max {}, purchased {}",
+                self.max_per_wallet,
+                purchase_count
+            );
+            return Err(NoMoreWalletPurchases.into());
+        }
+
+        Ok(new_purchase_count)
+    }
+
+    pub fn process_claim(&mut self, quantity: u32, variant: Option<u8>) {
+        self.current_supply += quantity as u64;
+        if let Some(variant) = variant.and_then(|variant| self.variants.get_mut(variant as usize)) {
+            variant.current_supply += quantity as u64;
+        }
+        self.order_count += 1;
+    }
+
+    /// escrowed orders are released or refunded
+    pub fn process_escrow_closed(&mut self) {
+        self.open_escrows = self.open_escrows.saturating_sub(1);
+    }
+
+    pub fn process_refund(&mut self, quantity: u32, variant: Option<u8>) {
+        self.current_supply = self.current_supply.saturating_sub(quantity as u64);
+        if let Some(variant) = variant.and_then(|variant| self.variants.get_mut(variant as usize)) {
+            variant.current_supply = variant.current_supply.saturating_sub(quantity as u64);
+        }
+    }
+
+    /// scope claim markers are tracked in: the shared claim pool if set, otherwise the product
+    pub fn claim_scope(&self, product: Pubkey) -> Pubkey {
+        self.claim_pool_scope.unwrap_or(product)
+    }
+
+    /// pools are namespaced by the wallet setting them, so only the seller's own products can
+    /// share them. the scope is kept until the pool changes, even if the authority does
+    pub fn update_claim_pool_scope(&mut self, old_claim_pool: Option<Pubkey>, signer: &Pubkey) {
+        if self.claim_pool != old_claim_pool {
+            self.claim_pool_scope = self.claim_pool.map(|claim_pool| {
+                Pubkey::new_from_array(hashv(&[signer.as_ref(), claim_pool.as_ref()]).to_bytes())
+            });
+        }
     }
 
-    pub fn process_user_claim(&self, claim_count: u32) -> Result<u32> {
-        let new_claim_count = claim_count + 1;
+    pub fn process_user_claim(&self, claim_count: u32, quantity: u32) -> Result<u32> {
+        let new_claim_count = claim_count.checked_add(quantity).ok_or(NumericalOverflow)?;
         if self.claims_per_edition > 0 && new_claim_count > self.claims_per_edition {
             msg!(
                 "claim amount reached for mint: max {}",
@@ -89,6 +592,10 @@ impl MerchProduct {
 
         Ok(new_claim_count)
     }
+
+    pub fn process_user_refund(&self, claim_count: u32, quantity: u32) -> u32 {
+        claim_count.saturating_sub(quantity)
+    }
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug)]
@@ -98,12 +605,307 @@ pub enum MaxSupply {
     FollowMasterEdition,
 }
 
+/// how referral commissions are charged
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum CommissionMode {
+    /// the buyer pays the commission on top of the payment
+    #[default]
+    OnTop,
+    /// the commission is taken out of the seller's share of the payment
+    FromSeller,
+}
+
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug)]
 pub struct PaymentConfig {
     pub tag: String,
     pub mint: Pubkey,
     pub amount: u64,
     pub recipient: Pubkey,
+    pub transfer_fee_mode: TransferFeeMode,
+    pub option: u8,
+    pub splits: Vec<PaymentSplit>,
+    pub auction: Option<DutchAuction>,
+    pub curve: Option<PricingCurve>,
+}
+
+impl PaymentConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(curve) = &self.curve {
+            if self.auction.is_some() || curve.tier_size() == 0 {
+                msg!(
+                    "invalid pricing curve for {}: tier size = {}, auctioned = {}",
+                    self.tag,
+                    curve.tier_size(),
+                    self.auction.is_some()
+                );
+                return Err(InvalidPricingCurve.into());
+            }
+        }
+
+        if let Some(auction) = &self.auction {
+            if auction.interval <= 0 || auction.floor_amount > self.amount {
+                msg!(
+                    "invalid auction for {}: interval = {}, floor = {}, start = {}",
+                    self.tag,
+                    auction.interval,
+                    auction.floor_amount,
+                    self.amount
+                );
+                return Err(InvalidAuction.into());
+            }
+        }
+
+        if self.splits.is_empty() {
+            return Ok(());
+        }
+
+        let total_share_bps = self
+            .splits
+            .iter()
+            .map(|split| split.share_bps as u64)
+            .sum::<u64>();
+        if total_share_bps != BASIS_POINTS_DENOMINATOR {
+            msg!(
+                "invalid splits for {}: shares sum to {} bps, expected {}",
+                self.tag,
+                total_share_bps,
+                BASIS_POINTS_DENOMINATOR
+            );
+            return Err(InvalidPaymentSplits.into());
+        }
+
+        Ok(())
+    }
+
+    /// recipients of `amount` with their shares.
+    /// the rounding remainder of split payments goes to the first split
+    pub fn split_amount(&self, amount: u64) -> Result<Vec<(Pubkey, u64)>> {
+        if self.splits.is_empty() {
+            return Ok(vec![(self.recipient, amount)]);
+        }
+
+        let mut shares = self
+            .splits
+            .iter()
+            .map(|split| {
+                Ok((
+                    split.recipient,
+                    amount
+                        .checked_mul(split.share_bps as u64)
+                        .ok_or(NumericalOverflow)?
+                        / BASIS_POINTS_DENOMINATOR,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let remainder = amount - shares.iter().map(|(_, share)| share).sum::<u64>();
+        shares[0].1 += remainder;
+
+        Ok(shares)
+    }
+
+    /// unit price at `now`, declining from `amount` if the payment is auctioned
+    pub fn price(&self, sale_start_at: Option<i64>, now: i64) -> u64 {
+        let Some(auction) = &self.auction else {
+            return self.amount;
+        };
+
+        let elapsed = now.saturating_sub(sale_start_at.unwrap_or(now)).max(0);
+        let steps = (elapsed / auction.interval) as u64;
+        self.amount
+            .saturating_sub(steps.saturating_mul(auction.step))
+            .max(auction.floor_amount)
+    }
+
+    /// amount charged for `quantity` units sold after `current_supply` units, and the
+    /// price of the last unit. units on a pricing curve are priced by their tier
+    pub fn quote(
+        &self,
+        current_supply: u64,
+        quantity: u32,
+        sale_start_at: Option<i64>,
+        now: i64,
+    ) -> Result<(u64, u64)> {
+        let Some(curve) = &self.curve else {
+            let unit_price = self.price(sale_start_at, now);
+            let amount = unit_price
+                .checked_mul(quantity as u64)
+                .ok_or(NumericalOverflow)?;
+            return Ok((amount, unit_price));
+        };
+
+        let tier_size = curve.tier_size();
+        let end_supply = current_supply
+            .checked_add(quantity as u64)
+            .ok_or(NumericalOverflow)?;
+        let mut supply = current_supply;
+        let mut amount = 0u64;
+        let mut unit_price = self.amount;
+        while supply < end_supply {
+            let tier = supply / tier_size;
+            let tier_end = (tier + 1).saturating_mul(tier_size).min(end_supply);
+            unit_price = curve.price(self.amount, tier)?;
+            amount = unit_price
+                .checked_mul(tier_end - supply)
+                .and_then(|tier_amount| amount.checked_add(tier_amount))
+                .ok_or(NumericalOverflow)?;
+            supply = tier_end;
+        }
+
+        Ok((amount, unit_price))
+    }
+}
+
+/// price rising with the supply sold, by tiers of `tier_size` units starting at
+/// `PaymentConfig.amount`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug)]
+pub enum PricingCurve {
+    /// price increases by `increment` every tier
+    Linear { tier_size: u64, increment: u64 },
+    /// price increases by `growth_bps` every tier, compounded
+    Exponential { tier_size: u64, growth_bps: u16 },
+}
+
+impl PricingCurve {
+    pub fn tier_size(&self) -> u64 {
+        match self {
+            Self::Linear { tier_size, .. } | Self::Exponential { tier_size, .. } => *tier_size,
+        }
+    }
+
+    /// unit price of the units in `tier`
+    pub fn price(&self, base: u64, tier: u64) -> Result<u64> {
+        match self {
+            Self::Linear { increment, .. } => Ok(tier
+                .checked_mul(*increment)
+                .and_then(|increase| base.checked_add(increase))
+                .ok_or(NumericalOverflow)?),
+            Self::Exponential { growth_bps, .. } => {
+                // (1 + growth)^tier in basis points, by squaring
+                let denominator = BASIS_POINTS_DENOMINATOR as u128;
+                let mut factor = denominator + *growth_bps as u128;
+                let mut growth = denominator;
+                let mut exponent = tier;
+                while exponent > 0 {
+                    if exponent & 1 == 1 {
+                        growth = growth.checked_mul(factor).ok_or(NumericalOverflow)? / denominator;
+                    }
+                    exponent >>= 1;
+                    if exponent > 0 {
+                        factor = factor.checked_mul(factor).ok_or(NumericalOverflow)? / denominator;
+                    }
+                }
+
+                let price = (base as u128)
+                    .checked_mul(growth)
+                    .ok_or(NumericalOverflow)?
+                    / denominator;
+                Ok(u64::try_from(price).map_err(|_| NumericalOverflow)?)
+            }
+        }
+    }
+}
+
+/// price declining by `step` every `interval` seconds after the sale starts,
+/// down to `floor_amount`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug)]
+pub struct DutchAuction {
+    pub floor_amount: u64,
+    pub interval: i64,
+    pub step: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug)]
+pub struct PaymentSplit {
+    pub recipient: Pubkey,
+    pub share_bps: u16,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TransferFeeMode {
+    Net,
+    Gross,
+}
+
+impl TransferFeeMode {
+    /// amount to transfer for a payment of `amount`
+    pub fn transfer_amount(&self, mint: &AccountInfo, amount: u64) -> Result<u64> {
+        match self {
+            // the buyer pays the transfer fee, the recipient receives `amount`
+            Self::Net => Ok(amount
+                .checked_add(inverse_transfer_fee(mint, amount)?)
+                .ok_or(NumericalOverflow)?),
+            // the transfer fee is deducted from `amount`
+            Self::Gross => Ok(amount),
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ProductVariant {
+    pub label: String,
+    pub max_supply: Option<u64>,
+    /// replaces the amount of each of the product's payments, in order
+    pub amounts: Option<Vec<u64>>,
+    pub current_supply: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug)]
+pub struct SalePhase {
+    pub name: String,
+    pub start_at: Option<i64>,
+    pub end_at: Option<i64>,
+    pub gate: PhaseGate,
+    pub price_bps: u16,
+    pub max_per_wallet: u32,
+}
+
+impl SalePhase {
+    pub fn is_active(&self, now: i64) -> bool {
+        !matches!(self.start_at, Some(start_at) if now < start_at)
+            && !matches!(self.end_at, Some(end_at) if end_at < now)
+    }
+
+    /// `amount` at the phase's price, `price_bps` of the full price
+    pub fn price(&self, amount: u64) -> Result<u64> {
+        Ok(
+            (amount as u128 * self.price_bps as u128 / BASIS_POINTS_DENOMINATOR as u128)
+                .try_into()
+                .map_err(|_| NumericalOverflow)?,
+        )
+    }
+
+    pub fn process_wallet_purchase(&self, purchase_count: u32, quantity: u32) -> Result<u32> {
+        let new_purchase_count = purchase_count
+            .checked_add(quantity)
+            .ok_or(NumericalOverflow)?;
+        if self.max_per_wallet > 0 && new_purchase_count > self.max_per_wallet {
+            msg!(
+                "phase {} wallet purchase limit reached: max {}, purchased {}",
+                self.name,
+                self.max_per_wallet,
+                purchase_count
+            );
+            return Err(NoMoreWalletPurchases.into());
+        }
+
+        Ok(new_purchase_count)
+    }
+}
+
+/// who can buy during a sale phase
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PhaseGate {
+    /// anyone
+    Public,
+    /// holders of an edition of `linked_master_nft` or an nft of `linked_collection`
+    Holders,
+    /// wallets on the `allowlist_root` allowlist
+    Allowlist,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug)]
+pub struct EscrowConfig {
+    pub timeout: i64,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug)]
@@ -115,18 +917,80 @@ pub struct MerchProductConfig {
 
     pub category: Option<String>,
     pub max_supply: Option<MaxSupply>,
+    pub variants: Option<Vec<ProductVariant>>,
 
     pub sale_start_at: Option<Option<i64>>,
     pub sale_end_at: Option<Option<i64>>,
+    pub paused: Option<bool>,
+    pub phases: Option<Vec<SalePhase>>,
 
     pub linked_master_nft: Option<Option<Pubkey>>,
+    pub linked_collection: Option<Option<Pubkey>>,
     pub claims_per_edition: Option<u32>,
+    pub claim_pool: Option<Option<Pubkey>>,
+    pub max_per_wallet: Option<u32>,
+
+    pub allowlist_root: Option<Option<[u8; 32]>>,
 
     pub payments: Option<Vec<PaymentConfig>>,
     pub affiliate_commission_bps: Option<u16>,
+    pub commission_mode: Option<CommissionMode>,
+
+    pub escrow: Option<Option<EscrowConfig>>,
+    pub auction_rebate: Option<bool>,
 }
 
 impl MerchProductConfig {
+    /// config containing only the fields of `new` that differ from `old`
+    pub fn diff(old: Option<&MerchProduct>, new: &MerchProduct) -> Self {
+        fn changed<T: Clone + PartialEq>(old: Option<&T>, new: &T) -> Option<T> {
+            (old != Some(new)).then(|| new.clone())
+        }
+
+        Self {
+            authority: changed(old.map(|old| &old.authority), &new.authority),
+
+            name: changed(old.map(|old| &old.name), &new.name),
+            uri: changed(old.map(|old| &old.uri), &new.uri),
+
+            category: changed(old.map(|old| &old.category), &new.category),
+            max_supply: changed(old.map(|old| &old.max_supply), &new.max_supply),
+            variants: changed(old.map(|old| &old.variants), &new.variants),
+
+            sale_start_at: changed(old.map(|old| &old.sale_start_at), &new.sale_start_at),
+            sale_end_at: changed(old.map(|old| &old.sale_end_at), &new.sale_end_at),
+            paused: changed(old.map(|old| &old.paused), &new.paused),
+            phases: changed(old.map(|old| &old.phases), &new.phases),
+
+            linked_master_nft: changed(
+                old.map(|old| &old.linked_master_nft),
+                &new.linked_master_nft,
+            ),
+            linked_collection: changed(
+                old.map(|old| &old.linked_collection),
+                &new.linked_collection,
+            ),
+            claims_per_edition: changed(
+                old.map(|old| &old.claims_per_edition),
+                &new.claims_per_edition,
+            ),
+            claim_pool: changed(old.map(|old| &old.claim_pool), &new.claim_pool),
+            max_per_wallet: changed(old.map(|old| &old.max_per_wallet), &new.max_per_wallet),
+
+            allowlist_root: changed(old.map(|old| &old.allowlist_root), &new.allowlist_root),
+
+            payments: changed(old.map(|old| &old.payments), &new.payments),
+            affiliate_commission_bps: changed(
+                old.map(|old| &old.affiliate_commission_bps),
+                &new.affiliate_commission_bps,
+            ),
+            commission_mode: changed(old.map(|old| &old.commission_mode), &new.commission_mode),
+
+            escrow: changed(old.map(|old| &old.escrow), &new.escrow),
+            auction_rebate: changed(old.map(|old| &old.auction_rebate), &new.auction_rebate),
+        }
+    }
+
     pub fn to_product(self, id: Pubkey) -> Result<MerchProduct> {
         Ok(MerchProduct {
             id,
@@ -150,18 +1014,42 @@ impl MerchProductConfig {
             })?,
             current_supply: 0,
             max_supply: self.max_supply.unwrap_or(MaxSupply::None),
+            variants: self
+                .variants
+                .unwrap_or_default()
+                .into_iter()
+                .map(|variant| ProductVariant {
+                    current_supply: 0,
+                    ..variant
+                })
+                .collect(),
 
             sale_start_at: self.sale_start_at.flatten(),
             sale_end_at: self.sale_end_at.flatten(),
+            paused: self.paused.unwrap_or_default(),
+            phases: self.phases.unwrap_or_default(),
 
             linked_master_nft: self.linked_master_nft.flatten(),
+            linked_collection: self.linked_collection.flatten(),
             claims_per_edition: self.claims_per_edition.unwrap_or_default(),
+            claim_pool: self.claim_pool.flatten(),
+            claim_pool_scope: None,
+            max_per_wallet: self.max_per_wallet.unwrap_or_default(),
+
+            allowlist_root: self.allowlist_root.flatten(),
 
             payments: self.payments.ok_or_else(|| {
                 msg!("missing payments");
                 MissingData
             })?,
             affiliate_commission_bps: self.affiliate_commission_bps.unwrap_or_default(),
+            commission_mode: self.commission_mode.unwrap_or_default(),
+
+            escrow: self.escrow.flatten(),
+            auction_rebate: self.auction_rebate.unwrap_or_default(),
+
+            order_count: 0,
+            open_escrows: 0,
         })
     }
 
@@ -176,19 +1064,336 @@ impl MerchProductConfig {
             category: self.category.unwrap_or(product.category),
             current_supply: product.current_supply,
             max_supply: self.max_supply.unwrap_or(product.max_supply),
+            // the supply sold of each variant is kept by index, see `assert_variants_kept`
+            variants: match self.variants {
+                Some(variants) => variants
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, variant)| ProductVariant {
+                        current_supply: product.variants.get(i).map_or(0, |old| old.current_supply),
+                        ..variant
+                    })
+                    .collect(),
+                None => product.variants,
+            },
 
             sale_start_at: self.sale_start_at.unwrap_or(product.sale_start_at),
             sale_end_at: self.sale_end_at.unwrap_or(product.sale_end_at),
+            paused: self.paused.unwrap_or(product.paused),
+            phases: self.phases.unwrap_or(product.phases),
 
             linked_master_nft: self.linked_master_nft.unwrap_or(product.linked_master_nft),
+            linked_collection: self.linked_collection.unwrap_or(product.linked_collection),
             claims_per_edition: self
                 .claims_per_edition
                 .unwrap_or(product.claims_per_edition),
+            claim_pool: self.claim_pool.unwrap_or(product.claim_pool),
+            claim_pool_scope: product.claim_pool_scope,
+            max_per_wallet: self.max_per_wallet.unwrap_or(product.max_per_wallet),
+
+            allowlist_root: self.allowlist_root.unwrap_or(product.allowlist_root),
 
             payments: self.payments.unwrap_or(product.payments),
             affiliate_commission_bps: self
                 .affiliate_commission_bps
                 .unwrap_or(product.affiliate_commission_bps),
+            commission_mode: self.commission_mode.unwrap_or(product.commission_mode),
+
+            escrow: self.escrow.unwrap_or(product.escrow),
+            auction_rebate: self.auction_rebate.unwrap_or(product.auction_rebate),
+
+            order_count: product.order_count,
+            open_escrows: product.open_escrows,
+        }
+    }
+}
+
+#[account]
+pub struct Order {
+    pub product: Pubkey,
+    pub buyer: Pubkey,
+    pub number: u64,
+    pub bump: u8,
+
+    pub quantity: u32,
+    pub variant: Option<u8>,
+    pub payment_option: u8,
+    pub payments: Vec<OrderPayment>,
+
+    pub referrer: Option<Pubkey>,
+    pub edition: Option<Pubkey>,
+    pub claim_scope: Option<Pubkey>,
+    pub allowlist_counted: bool,
+    pub wallet_counted: bool,
+    pub phase_counted: Option<u8>,
+
+    pub created_at: i64,
+
+    pub status: OrderStatus,
+    pub escrow_expires_at: Option<i64>,
+}
+
+impl Order {
+    pub fn space(payment_count: usize) -> usize {
+        8 // discriminator
+            + 32 // product
+            + 32 // buyer
+            + 8 // number
+            + 1 // bump
+            + 4 // quantity
+            + 1 + 1 // variant
+            + 1 // payment_option
+            + 4 + payment_count * OrderPayment::SPACE // payments
+            + 1 + 32 // referrer
+            + 1 + 32 // edition
+            + 1 + 32 // claim_scope
+            + 1 // allowlist_counted
+            + 1 // wallet_counted
+            + 1 + 1 // phase_counted
+            + 8 // created_at
+            + 1 // status
+            + 1 + 8 // escrow_expires_at
+    }
+
+    pub fn assert_status(&self, status: OrderStatus) -> Result<()> {
+        if self.status != status {
+            msg!(
+                "invalid order status: expected {:?}, got {:?}",
+                status,
+                self.status
+            );
+            return Err(InvalidOrderStatus.into());
         }
+
+        Ok(())
     }
 }
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OrderStatus {
+    Paid,
+    Escrowed,
+    Released,
+    Refunded,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug)]
+pub struct OrderPayment {
+    pub mint: Pubkey,
+    pub recipient: Pubkey,
+    pub unit_price: u64,
+    pub amount: u64,
+    pub referrer_amount: u64,
+    pub platform_fee: u64,
+    pub fee_recipient: Pubkey,
+    pub transfer_fee_mode: TransferFeeMode,
+    pub escrowed_amount: u64,
+}
+
+impl OrderPayment {
+    pub const SPACE: usize = 32 + 32 + 8 + 8 + 8 + 8 + 32 + 1 + 8;
+
+    /// total paid by the buyer for this payment
+    pub fn total_amount(&self) -> Result<u64> {
+        Ok(self
+            .amount
+            .checked_add(self.referrer_amount)
+            .and_then(|amount| amount.checked_add(self.platform_fee))
+            .ok_or(NumericalOverflow)?)
+    }
+}
+
+#[account]
+pub struct Config {
+    pub admin: Pubkey,
+    pub platform_fee_bps: u16,
+    pub fee_recipients: Vec<FeeRecipient>,
+    pub paused: bool,
+    pub bump: u8,
+}
+
+impl Config {
+    pub fn space(fee_recipient_count: usize) -> usize {
+        8 // discriminator
+            + 32 // admin
+            + 2 // platform_fee_bps
+            + 4 + fee_recipient_count * FeeRecipient::SPACE // fee_recipients
+            + 1 // paused
+            + 1 // bump
+    }
+
+    pub fn assert_not_paused(&self) -> Result<()> {
+        if self.paused {
+            msg!("program is paused");
+            return Err(ProgramPaused.into());
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.platform_fee_bps as u64 > BASIS_POINTS_DENOMINATOR {
+            msg!(
+                "invalid platform fee: {} bps exceeds {}",
+                self.platform_fee_bps,
+                BASIS_POINTS_DENOMINATOR
+            );
+            return Err(InvalidPlatformFee.into());
+        }
+
+        for (i, fee_recipient) in self.fee_recipients.iter().enumerate() {
+            if self.fee_recipients[..i]
+                .iter()
+                .any(|other| other.mint == fee_recipient.mint)
+            {
+                msg!("duplicate fee recipient for {}", fee_recipient.mint);
+                return Err(InvalidPlatformFee.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// platform fee on `amount` of `mint` and its recipient.
+    /// no fee is charged for mints without a fee recipient
+    pub fn platform_fee(&self, mint: &Pubkey, amount: u64) -> Result<Option<(Pubkey, u64)>> {
+        let Some(fee_recipient) = self
+            .fee_recipients
+            .iter()
+            .find(|fee_recipient| fee_recipient.mint == *mint)
+        else {
+            return Ok(None);
+        };
+
+        let fee = amount
+            .checked_mul(self.platform_fee_bps as u64)
+            .ok_or(NumericalOverflow)?
+            / BASIS_POINTS_DENOMINATOR;
+        Ok((fee > 0).then_some((fee_recipient.recipient, fee)))
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug)]
+pub struct FeeRecipient {
+    pub mint: Pubkey,
+    pub recipient: Pubkey,
+}
+
+impl FeeRecipient {
+    pub const SPACE: usize = 32 + 32;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug, Default)]
+pub struct ConfigArgs {
+    pub admin: Option<Pubkey>,
+    pub platform_fee_bps: Option<u16>,
+    pub fee_recipients: Option<Vec<FeeRecipient>>,
+    pub paused: Option<bool>,
+}
+
+impl ConfigArgs {
+    pub fn update_config(self, config: &Config) -> Config {
+        Config {
+            admin: self.admin.unwrap_or(config.admin),
+            platform_fee_bps: self.platform_fee_bps.unwrap_or(config.platform_fee_bps),
+            fee_recipients: self
+                .fee_recipients
+                .unwrap_or_else(|| config.fee_recipients.clone()),
+            paused: self.paused.unwrap_or(config.paused),
+            bump: config.bump,
+        }
+    }
+}
+
+#[account]
+pub struct Affiliate {
+    pub wallet: Pubkey,
+    /// product or seller the affiliate is registered with
+    pub scope: Pubkey,
+    pub commission_bps: Option<u16>,
+    pub active: bool,
+    pub approved: bool,
+    pub bump: u8,
+}
+
+impl Affiliate {
+    pub const SPACE: usize = 8 // discriminator
+        + 32 // wallet
+        + 32 // scope
+        + 1 + 2 // commission_bps
+        + 1 // active
+        + 1 // approved
+        + 1; // bump
+
+    /// commission rate of `referrer` for `product`
+    pub fn commission_bps(&self, referrer: &Pubkey, product: &MerchProduct) -> Result<u16> {
+        if self.wallet != *referrer || (self.scope != product.id && self.scope != product.authority)
+        {
+            msg!(
+                "affiliate {} of {} is not registered for {}",
+                self.wallet,
+                self.scope,
+                referrer
+            );
+            return Err(AccountMismatch.into());
+        }
+        if !self.approved {
+            msg!("affiliate {} not approved", self.wallet);
+            return Err(AffiliateNotApproved.into());
+        }
+        if !self.active {
+            msg!("affiliate {} inactive", self.wallet);
+            return Err(AffiliateInactive.into());
+        }
+
+        Ok(self
+            .commission_bps
+            .unwrap_or(product.affiliate_commission_bps))
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug, Default)]
+pub struct AffiliateConfig {
+    pub commission_bps: Option<Option<u16>>,
+    pub active: Option<bool>,
+    pub approved: Option<bool>,
+}
+
+/// sales of a product or generated by a referrer
+#[account]
+#[derive(Default)]
+pub struct SalesStats {
+    pub subject: Pubkey,
+    pub sales_count: u64,
+    pub units_sold: u64,
+    pub volume: Vec<MintVolume>,
+    pub last_sale_at: i64,
+}
+
+impl SalesStats {
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        Ok([&Self::DISCRIMINATOR, self.try_to_vec()?.as_slice()].concat())
+    }
+
+    /// records a sale of `quantity` units for the amounts paid in each mint
+    pub fn record_sale(&mut self, quantity: u32, amounts: &[(Pubkey, u64)], timestamp: i64) {
+        self.sales_count = self.sales_count.saturating_add(1);
+        self.units_sold = self.units_sold.saturating_add(quantity as u64);
+        for (mint, amount) in amounts {
+            match self.volume.iter_mut().find(|volume| volume.mint == *mint) {
+                Some(volume) => volume.amount = volume.amount.saturating_add(*amount as u128),
+                None => self.volume.push(MintVolume {
+                    mint: *mint,
+                    amount: *amount as u128,
+                }),
+            }
+        }
+        self.last_sale_at = timestamp;
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug)]
+pub struct MintVolume {
+    pub mint: Pubkey,
+    pub amount: u128,
+}
@@ -0,0 +1,247 @@
+use anchor_lang::{prelude::*, solana_program::hash::hashv, system_program};
+use anchor_spl::{token, token_interface};
+
+use crate::{errors::*, state::SalesStats};
+
+pub fn assert_token_account_mint(
+    token_account: &AccountInfo,
+    mint: &Pubkey,
+) -> Result<token_interface::TokenAccount> {
+    let token_account_data =
+        token_interface::TokenAccount::try_deserialize(&mut &**token_account.try_borrow_data()?)?;
+    if token_account_data.mint != *mint {
+        msg!(
+            "invalid mint for {}: expected {}, got {}",
+            token_account.key(),
+            mint,
+            token_account_data.mint
+        );
+        return Err(AccountMismatch.into());
+    }
+
+    Ok(token_account_data)
+}
+
+pub fn assert_token_account(
+    token_account: &AccountInfo,
+    mint: &Pubkey,
+    owner: &Pubkey,
+) -> Result<token_interface::TokenAccount> {
+    let token_account_data = assert_token_account_mint(token_account, mint)?;
+    if token_account_data.owner != *owner {
+        msg!(
+            "invalid owner for {}: expected {}, got {}",
+            token_account.key(),
+            owner,
+            token_account_data.owner
+        );
+        return Err(AccountMismatch.into());
+    }
+
+    Ok(token_account_data)
+}
+
+pub fn assert_nft_holder(
+    token_account: &Account<token::TokenAccount>,
+    mint: &Pubkey,
+    owner: &Pubkey,
+) -> Result<()> {
+    if token_account.mint != *mint {
+        msg!(
+            "invalid mint for {}: expected {}, got {}",
+            token_account.key(),
+            mint,
+            token_account.mint
+        );
+        return Err(AccountMismatch.into());
+    }
+    if token_account.owner != *owner {
+        msg!(
+            "invalid owner for {}: expected {}, got {}",
+            token_account.key(),
+            owner,
+            token_account.owner
+        );
+        return Err(AccountMismatch.into());
+    }
+    if token_account.amount != 1 {
+        msg!(
+            "nft not held in {}: amount = {}",
+            token_account.key(),
+            token_account.amount
+        );
+        return Err(AccountMismatch.into());
+    }
+
+    Ok(())
+}
+
+/// creates a program owned pda like anchor's `init`: if anyone already sent lamports to it,
+/// it is topped up to rent exemption, then allocated and assigned instead
+pub fn create_pda<'info>(
+    pda: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program_account: &AccountInfo<'info>,
+    signer_seeds: &[&[u8]],
+    space: usize,
+) -> Result<()> {
+    let rent_exempt_lamports = Rent::get()?.minimum_balance(space);
+    if pda.lamports() == 0 {
+        return system_program::create_account(
+            CpiContext::new(
+                system_program_account.clone(),
+                system_program::CreateAccount {
+                    from: payer.clone(),
+                    to: pda.clone(),
+                },
+            )
+            .with_signer(&[signer_seeds]),
+            rent_exempt_lamports,
+            space as u64,
+            &crate::ID,
+        );
+    }
+
+    let lamport_deficit = rent_exempt_lamports.saturating_sub(pda.lamports());
+    if lamport_deficit > 0 {
+        system_program::transfer(
+            CpiContext::new(
+                system_program_account.clone(),
+                system_program::Transfer {
+                    from: payer.clone(),
+                    to: pda.clone(),
+                },
+            ),
+            lamport_deficit,
+        )?;
+    }
+    system_program::allocate(
+        CpiContext::new(
+            system_program_account.clone(),
+            system_program::Allocate {
+                account_to_allocate: pda.clone(),
+            },
+        )
+        .with_signer(&[signer_seeds]),
+        space as u64,
+    )?;
+    system_program::assign(
+        CpiContext::new(
+            system_program_account.clone(),
+            system_program::Assign {
+                account_to_assign: pda.clone(),
+            },
+        )
+        .with_signer(&[signer_seeds]),
+        &crate::ID,
+    )
+}
+
+/// loads the count stored in a marker pda, creating the marker if it does not exist yet
+pub fn load_or_create_marker<'info>(
+    marker: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program_account: &AccountInfo<'info>,
+    signer_seeds: &[&[u8]],
+) -> Result<u32> {
+    let mut count = 0u32;
+    if *marker.owner != crate::ID {
+        let count_len = count.try_to_vec()?.as_slice().len();
+        create_pda(
+            marker,
+            payer,
+            system_program_account,
+            signer_seeds,
+            count_len,
+        )?;
+    } else {
+        count = u32::deserialize(&mut &(**marker.try_borrow_data()?))?;
+    }
+
+    Ok(count)
+}
+
+pub fn save_marker(marker: &AccountInfo, count: u32) -> Result<()> {
+    marker
+        .try_borrow_mut_data()?
+        .copy_from_slice(&count.try_to_vec()?);
+
+    Ok(())
+}
+
+/// loads the stats stored in a stats pda, creating the pda if it does not exist yet
+pub fn load_or_create_stats<'info>(
+    stats: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program_account: &AccountInfo<'info>,
+    signer_seeds: &[&[u8]],
+    subject: Pubkey,
+) -> Result<SalesStats> {
+    if *stats.owner == crate::ID {
+        return SalesStats::try_deserialize(&mut &(**stats.try_borrow_data()?));
+    }
+
+    let stats_data = SalesStats {
+        subject,
+        ..Default::default()
+    };
+    let stats_len = stats_data.serialize()?.len();
+    create_pda(
+        stats,
+        payer,
+        system_program_account,
+        signer_seeds,
+        stats_len,
+    )?;
+
+    Ok(stats_data)
+}
+
+/// saves stats, growing the pda when a new mint is recorded
+pub fn save_stats<'info>(
+    stats: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program_account: &AccountInfo<'info>,
+    stats_data: &SalesStats,
+) -> Result<()> {
+    let serialized_stats = stats_data.serialize()?;
+    let stats_len = serialized_stats.len();
+    if stats_len > stats.data_len() {
+        let lamport_deficit = Rent::get()?
+            .minimum_balance(stats_len)
+            .saturating_sub(stats.lamports());
+        if lamport_deficit > 0 {
+            system_program::transfer(
+                CpiContext::new(
+                    system_program_account.clone(),
+                    system_program::Transfer {
+                        from: payer.clone(),
+                        to: stats.clone(),
+                    },
+                ),
+                lamport_deficit,
+            )?;
+        }
+        stats.realloc(stats_len, false)?;
+    }
+
+    stats.try_borrow_mut_data()?[..stats_len].copy_from_slice(&serialized_stats);
+
+    Ok(())
+}
+
+/// verifies a proof against a merkle tree built with sorted pairs,
+/// leaves are prefixed with 0x00 and nodes with 0x01
+pub fn verify_merkle_proof(root: &[u8; 32], leaf: &[u8], proof: &[[u8; 32]]) -> bool {
+    let mut node = hashv(&[&[0x00], leaf]).to_bytes();
+    for sibling in proof {
+        node = if node <= *sibling {
+            hashv(&[&[0x01], &node, sibling])
+        } else {
+            hashv(&[&[0x01], sibling, &node])
+        }
+        .to_bytes();
+    }
+
+    node == *root
+}
@@ -8,6 +8,11 @@ import { sol, tokenAmount } from "./utils";
 export const mintKeypair = Keypair.generate();
 export const mint = mintKeypair.publicKey;
 
+// token-2022 mint with a 1% transfer fee
+export const feeMintKeypair = Keypair.generate();
+export const feeMint = feeMintKeypair.publicKey;
+export const FEE_MINT_TRANSFER_FEE_BPS = 100;
+
 export const admin = Keypair.generate();
 export const user = Keypair.generate();
 
@@ -20,12 +25,20 @@ export const DEFAULT_PRODUCT_CONFIG: IdlTypes<FosterStudio>["merchProductConfig"
 
     category: "category",
     maxSupply: { none: {} },
+    variants: [],
 
     saleStartAt: null,
     saleEndAt: null,
+    paused: false,
+    phases: [],
 
     linkedMasterNft: null,
+    linkedCollection: null,
     claimsPerEdition: null,
+    claimPool: null,
+    maxPerWallet: null,
+
+    allowlistRoot: null,
 
     payments: [
       {
@@ -34,6 +47,11 @@ export const DEFAULT_PRODUCT_CONFIG: IdlTypes<FosterStudio>["merchProductConfig"
         // 0.1 sol
         amount: sol(0.1),
         recipient: admin.publicKey,
+        transferFeeMode: { net: {} },
+        option: 0,
+        splits: [],
+        auction: null,
+        curve: null,
       },
       {
         tag: "token amount",
@@ -41,7 +59,16 @@ export const DEFAULT_PRODUCT_CONFIG: IdlTypes<FosterStudio>["merchProductConfig"
         // 100 tokens
         amount: tokenAmount(100, 6),
         recipient: getAssociatedTokenAddressSync(mint, admin.publicKey),
+        transferFeeMode: { net: {} },
+        option: 0,
+        splits: [],
+        auction: null,
+        curve: null,
       },
     ],
     affiliateCommissionBps: 100, // 1%
+    commissionMode: { onTop: {} },
+
+    escrow: null,
+    auctionRebate: false,
   };
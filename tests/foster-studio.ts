@@ -3,17 +3,45 @@ import { AnchorError } from "@coral-xyz/anchor";
 import {
   createAssociatedTokenAccountIdempotentInstruction,
   getAssociatedTokenAddressSync,
+  TOKEN_2022_PROGRAM_ID,
 } from "@solana/spl-token";
 import * as assert from "assert";
+import { createHash } from "crypto";
 
-import { Keypair, LAMPORTS_PER_SOL } from "./web3";
-import { admin, user, DEFAULT_PRODUCT_CONFIG, mint } from "./constants";
+import {
+  Keypair,
+  LAMPORTS_PER_SOL,
+  PublicKey,
+  sendAndConfirmTransaction,
+  SystemProgram,
+  Transaction,
+} from "./web3";
+import {
+  admin,
+  user,
+  DEFAULT_PRODUCT_CONFIG,
+  mint,
+  feeMint,
+} from "./constants";
 import setupProgram from "./setup";
 import {
   buyProduct,
   buyProductBuilder,
   createProduct,
   findClaimMarkerPda,
+  getClaimScope,
+  findNftClaimMarkerPda,
+  claimOrderRefundBuilder,
+  findOrderPda,
+  getCpiEvents,
+  registerAffiliate,
+  findProductStatsPda,
+  findPurchaseMarkerPda,
+  findReferrerStatsPda,
+  ExpectedTerms,
+  getExpectedTerms,
+  refundOrderBuilder,
+  releaseOrderBuilder,
   studio,
 } from "./lib";
 import {
@@ -22,9 +50,15 @@ import {
   getTokenBalanceDelta,
   invertPromise,
   sleep,
+  sol,
   unixTimestamp,
 } from "./utils";
-import { createAndMintNft, createPrint } from "./mplTokenMetadata";
+import {
+  createAndMintNft,
+  createPrint,
+  verifyCollection,
+} from "./mplTokenMetadata";
+import { buildAllowlist } from "./merkle";
 
 describe("foster-studio", () => {
   const provider = anchor.AnchorProvider.env();
@@ -43,7 +77,9 @@ describe("foster-studio", () => {
       ...DEFAULT_PRODUCT_CONFIG,
       id: productId,
       claimsPerEdition: 0,
+      maxPerWallet: 0,
       currentSupply: "0",
+      orderCount: "0",
     });
   });
 
@@ -69,11 +105,72 @@ describe("foster-studio", () => {
     );
     assert.equal(
       getBalanceDelta(productDeletionTx, admin.publicKey),
-      3111120,
+      3473040,
       "authority was refunded rent"
     );
   });
 
+  it("product changes emit events", async () => {
+    const id = Keypair.generate();
+    const { signature: creationSignature } = await createProduct({ id });
+    await sleep(1000);
+
+    const [creationEvent] = await getCpiEvents(connection, creationSignature);
+    assert.equal(creationEvent.name, "productConfigured");
+    assert.equal(creationEvent.data.created, true);
+    deepStrictEqual(creationEvent.data.changes.name, DEFAULT_PRODUCT_CONFIG.name);
+
+    const { signature: updateSignature } = await createProduct({
+      id,
+      overrides: { name: "Updated Product" },
+    });
+    await sleep(1000);
+
+    const [updateEvent] = await getCpiEvents(connection, updateSignature);
+    assert.equal(updateEvent.name, "productConfigured");
+    assert.equal(updateEvent.data.created, false);
+    deepStrictEqual(updateEvent.data.changes, {
+      authority: null,
+      name: "Updated Product",
+      uri: null,
+      category: null,
+      maxSupply: null,
+      variants: null,
+      saleStartAt: null,
+      saleEndAt: null,
+      paused: null,
+      phases: null,
+      linkedMasterNft: null,
+      linkedCollection: null,
+      claimsPerEdition: null,
+      claimPool: null,
+      maxPerWallet: null,
+      allowlistRoot: null,
+      payments: null,
+      affiliateCommissionBps: null,
+      commissionMode: null,
+      escrow: null,
+      auctionRebate: null,
+    });
+
+    const deletionSignature = await studio.methods
+      .deleteProduct()
+      .accounts({
+        authority: admin.publicKey,
+        product: id.publicKey,
+      })
+      .signers([admin])
+      .rpc();
+    await sleep(1000);
+
+    deepStrictEqual(await getCpiEvents(connection, deletionSignature), [
+      {
+        name: "productDeleted",
+        data: { product: id.publicKey, authority: admin.publicKey },
+      },
+    ]);
+  });
+
   it("start time is verified", async () => {
     const { productId } = await createProduct({
       overrides: {
@@ -147,9 +244,123 @@ describe("foster-studio", () => {
     );
   });
 
+  it("buy product records order", async () => {
+    const referrer = Keypair.generate();
+    const { productId } = await createProduct();
+    await registerAffiliate({ wallet: referrer, scope: admin.publicKey });
+
+    const buyBuilder = await buyProductBuilder({
+      productId,
+      buyer: user,
+      referrer: referrer.publicKey,
+      quantity: 2,
+    });
+    const buySignature = await buyBuilder
+      .preInstructions([
+        createAssociatedTokenAccountIdempotentInstruction(
+          user.publicKey,
+          getAssociatedTokenAddressSync(mint, referrer.publicKey),
+          referrer.publicKey,
+          mint
+        ),
+      ])
+      .rpc();
+    await sleep(2000);
+
+    const orderId = findOrderPda(productId, user.publicKey, new anchor.BN(0));
+    const order = await studio.account.order.fetch(orderId);
+    assert.ok(order.createdAt.toNumber() > 0);
+    deepStrictEqual(
+      { ...order, createdAt: undefined },
+      {
+        product: productId,
+        buyer: user.publicKey,
+        number: "0",
+        bump: order.bump,
+
+        quantity: 2,
+        variant: null,
+        paymentOption: 0,
+        payments: [
+          {
+            mint: PublicKey.default,
+            recipient: admin.publicKey,
+            unitPrice: (0.1 * LAMPORTS_PER_SOL).toString(),
+            amount: (0.2 * LAMPORTS_PER_SOL).toString(),
+            referrerAmount: (0.002 * LAMPORTS_PER_SOL).toString(),
+            platformFee: "0",
+            feeRecipient: PublicKey.default,
+            transferFeeMode: { net: {} },
+            escrowedAmount: "0",
+          },
+          {
+            mint,
+            recipient: getAssociatedTokenAddressSync(mint, admin.publicKey),
+            unitPrice: (100n * 1_000_000n).toString(),
+            amount: (200n * 1_000_000n).toString(),
+            referrerAmount: (2n * 1_000_000n).toString(),
+            platformFee: "0",
+            feeRecipient: PublicKey.default,
+            transferFeeMode: { net: {} },
+            escrowedAmount: "0",
+          },
+        ],
+
+        referrer: referrer.publicKey,
+        edition: null,
+        claimScope: null,
+        allowlistCounted: false,
+        walletCounted: false,
+        phaseCounted: null,
+
+        createdAt: undefined,
+
+        status: { paid: {} },
+        escrowExpiresAt: null,
+      }
+    );
+
+    const product = await studio.account.merchProduct.fetch(productId);
+    assert.equal(product.orderCount.toString(), "1");
+
+    const [purchaseEvent] = await getCpiEvents(connection, buySignature);
+    assert.equal(purchaseEvent.name, "productPurchased");
+    deepStrictEqual(
+      {
+        order: purchaseEvent.data.order,
+        quantity: purchaseEvent.data.quantity,
+        currentSupply: purchaseEvent.data.currentSupply,
+      },
+      { order: orderId, quantity: 2, currentSupply: "2" }
+    );
+  });
+
+  it("order numbers are unique per buyer", async () => {
+    const { productId } = await createProduct();
+    // both buyers read the product before either purchase lands
+    const product = await studio.account.merchProduct.fetch(productId);
+
+    await buyProduct({ productId, buyer: admin, product });
+    await buyProduct({ productId, buyer: user, product });
+    await sleep(1000);
+
+    const orders = await studio.account.order.fetchMultiple([
+      findOrderPda(productId, admin.publicKey, new anchor.BN(0)),
+      findOrderPda(productId, user.publicKey, new anchor.BN(0)),
+    ]);
+    deepStrictEqual(
+      orders.map((order) => order.buyer),
+      [admin.publicKey, user.publicKey]
+    );
+
+    // an order number cannot be reused by the same buyer
+    await invertPromise(buyProduct({ productId, buyer: user, product }));
+  });
+
   it("buy product with referrer", async () => {
     const referrer = Keypair.generate();
     const { productId } = await createProduct();
+    await registerAffiliate({ wallet: referrer, scope: admin.publicKey });
 
     const buyBuilder = await buyProductBuilder({
       productId,
@@ -192,6 +403,64 @@ describe("foster-studio", () => {
     );
   });
 
+  it("token payment accounts are validated", async () => {
+    const { productId } = await createProduct();
+    const product = await studio.account.merchProduct.fetch(productId);
+
+    // rerouting the token payment to another account fails
+    const reroutedBuyError = await invertPromise<AnchorError>(
+      buyProduct({
+        productId,
+        buyer: user,
+        product: {
+          ...product,
+          payments: product.payments.map((payment) =>
+            payment.mint.equals(mint)
+              ? {
+                  ...payment,
+                  recipient: getAssociatedTokenAddressSync(
+                    mint,
+                    user.publicKey
+                  ),
+                }
+              : payment
+          ),
+        },
+      })
+    );
+
+    deepStrictEqual(reroutedBuyError.error, {
+      errorCode: { code: "AccountMismatch", number: 6009 },
+      errorMessage: "Account mismatch",
+      comparedValues: undefined,
+      origin: undefined,
+    });
+
+    // referrer ata must be owned by the referrer
+    const referrer = Keypair.generate();
+    await registerAffiliate({ wallet: referrer, scope: admin.publicKey });
+    const buyBuilder = await buyProductBuilder({
+      productId,
+      buyer: user,
+      product,
+      referrer: referrer.publicKey,
+    });
+    const remainingAccounts = buyBuilder["_remainingAccounts"];
+    remainingAccounts[remainingAccounts.length - 1] = {
+      pubkey: getAssociatedTokenAddressSync(mint, admin.publicKey),
+      isSigner: false,
+      isWritable: true,
+    };
+    const referrerBuyError = await invertPromise<AnchorError>(
+      buyBuilder.rpc()
+    );
+
+    deepStrictEqual(referrerBuyError.error.errorCode, {
+      code: "AccountMismatch",
+      number: 6009,
+    });
+  });
+
   it("buy product with max supply", async () => {
     const { productId } = await createProduct({
       overrides: {
@@ -217,13 +486,83 @@ describe("foster-studio", () => {
       ...DEFAULT_PRODUCT_CONFIG,
       id: productId,
       currentSupply: "1",
+      orderCount: "1",
       maxSupply: {
         some: {
           "0": "1",
         },
       },
       claimsPerEdition: 0,
+      maxPerWallet: 0,
+    });
+
+    // attempting to exceed max supply fails
+    const buyError = await invertPromise<AnchorError>(
+      buyProduct({
+        productId,
+        buyer: user,
+      })
+    );
+
+    deepStrictEqual(buyError.error, {
+      errorCode: { code: "NoMoreSupply", number: 6004 },
+      errorMessage: "No more supply",
+      comparedValues: undefined,
+      origin: undefined,
+    });
+  });
+
+  it("buy product with quantity", async () => {
+    const { productId } = await createProduct({
+      overrides: {
+        maxSupply: { some: [new anchor.BN(3)] },
+      },
+    });
+
+    // zero quantity fails
+    const zeroQuantityBuyError = await invertPromise<AnchorError>(
+      buyProduct({
+        productId,
+        buyer: user,
+        quantity: 0,
+      })
+    );
+
+    deepStrictEqual(zeroQuantityBuyError.error, {
+      errorCode: { code: "InvalidQuantity", number: 6010 },
+      errorMessage: "Invalid quantity",
+      comparedValues: undefined,
+      origin: undefined,
+    });
+
+    const buySignature = await buyProduct({
+      productId,
+      buyer: user,
+      quantity: 3,
     });
+    await sleep(2000);
+
+    const buyTx = await connection.getParsedTransaction(
+      buySignature,
+      "confirmed"
+    );
+
+    deepStrictEqual(
+      [getBalanceDelta(buyTx, admin.publicKey)],
+      [0.3 * LAMPORTS_PER_SOL],
+      "sol payments processed"
+    );
+    deepStrictEqual(
+      [
+        getTokenBalanceDelta(buyTx, mint, user.publicKey),
+        getTokenBalanceDelta(buyTx, mint, admin.publicKey),
+      ],
+      [-300n * 1_000_000n, 300n * 1_000_000n],
+      "token payments processed"
+    );
+
+    const product = await studio.account.merchProduct.fetch(productId);
+    assert.equal(product.currentSupply.toString(), "3");
 
     // attempting to exceed max supply fails
     const buyError = await invertPromise<AnchorError>(
@@ -241,6 +580,81 @@ describe("foster-studio", () => {
     });
   });
 
+  it("buy product with allowlist", async () => {
+    const allowlist = buildAllowlist([
+      { wallet: user.publicKey, allocation: 2 },
+      { wallet: admin.publicKey, allocation: 0 },
+      { wallet: Keypair.generate().publicKey, allocation: 1 },
+    ]);
+    const { productId } = await createProduct({
+      overrides: {
+        allowlistRoot: allowlist.root,
+      },
+    });
+
+    // buying without a proof fails
+    const noProofBuyError = await invertPromise<AnchorError>(
+      buyProduct({
+        productId,
+        buyer: user,
+      })
+    );
+    deepStrictEqual(noProofBuyError.error, {
+      errorCode: { code: "NotAllowlisted", number: 6014 },
+      errorMessage: "Buyer not on allowlist",
+      comparedValues: undefined,
+      origin: undefined,
+    });
+
+    // a proof for a different allocation fails
+    const wrongAllocationBuyError = await invertPromise<AnchorError>(
+      buyProduct({
+        productId,
+        buyer: user,
+        allowlistProof: {
+          allocation: 5,
+          proof: allowlist.getProof({ wallet: user.publicKey, allocation: 2 }),
+        },
+      })
+    );
+    deepStrictEqual(wrongAllocationBuyError.error.errorCode, {
+      code: "NotAllowlisted",
+      number: 6014,
+    });
+
+    const allowlistProof = {
+      allocation: 2,
+      proof: allowlist.getProof({ wallet: user.publicKey, allocation: 2 }),
+    };
+    await buyProduct({
+      productId,
+      buyer: user,
+      quantity: 2,
+      allowlistProof,
+    });
+    await sleep(2000);
+
+    const allowlistMarkerAccount = await connection.getAccountInfo(
+      findAllowlistMarkerPda(productId, user.publicKey)
+    );
+    deepStrictEqual(allowlistMarkerAccount.data.toString("hex"), "02000000");
+
+    // attempting to exceed the allocation fails
+    const exceededAllocationBuyError = await invertPromise<AnchorError>(
+      buyProduct({
+        productId,
+        buyer: user,
+        allowlistProof,
+      })
+    );
+    deepStrictEqual(exceededAllocationBuyError.error, {
+      errorCode: { code: "NoMoreAllocation", number: 6016 },
+      errorMessage: "No more allowlist allocation",
+      comparedValues: undefined,
+      origin: undefined,
+    });
+  });
+
   it("buy product with linked master edition", async () => {
     const { nftMint } = await createAndMintNft({
       connection,
@@ -286,18 +700,38 @@ describe("foster-studio", () => {
       ...DEFAULT_PRODUCT_CONFIG,
       id: productId,
       currentSupply: "1",
+      orderCount: "1",
       linkedMasterNft: nftMint,
       maxSupply: {
         followMasterEdition: {},
       },
       claimsPerEdition: 0,
+      maxPerWallet: 0,
     });
 
     const claimMarkerAccount = await connection.getAccountInfo(
-      findClaimMarkerPda(editionMint)
+      findClaimMarkerPda(productId, editionMint)
     );
     deepStrictEqual(claimMarkerAccount.data.toString("hex"), "01000000");
 
+    // attempting to buy with an edition held by someone else fails
+    const notHolderBuyError = await invertPromise<AnchorError>(
+      buyProduct({
+        productId,
+        buyer: admin,
+        edition: editionMint,
+        nftTokenAccount: getAssociatedTokenAddressSync(
+          editionMint,
+          user.publicKey
+        ),
+      })
+    );
+
+    deepStrictEqual(notHolderBuyError.error.errorCode, {
+      code: "AccountMismatch",
+      number: 6009,
+    });
+
     // attempting to buy without edition fails
     const noEditionBuyError = await invertPromise<AnchorError>(
       buyProduct({
@@ -330,7 +764,7 @@ describe("foster-studio", () => {
     });
   });
 
-  it("buy product with linked master edition and multiple claims", async () => {
+  it("paid purchase is refunded by the seller", async () => {
     const { nftMint } = await createAndMintNft({
       connection,
       payer: admin,
@@ -344,8 +778,7 @@ describe("foster-studio", () => {
     const { productId } = await createProduct({
       overrides: {
         linkedMasterNft: nftMint,
-        maxSupply: { some: [new anchor.BN(3)] },
-        claimsPerEdition: 2,
+        claimsPerEdition: 1,
       },
     });
 
@@ -357,58 +790,152 @@ describe("foster-studio", () => {
       editionNumber: 1,
     });
 
-    const buySignature = await buyProduct({
+    await buyProduct({
       productId,
       buyer: user,
       edition: editionMint,
     });
     await sleep(2000);
 
-    // less than max supply works
-    const buyTx = await connection.getParsedTransaction(
-      buySignature,
-      "confirmed"
+    const orderId = findOrderPda(productId, user.publicKey, new anchor.BN(0));
+    // the product cannot be deleted under an open escrow
+    const deleteProductBuilder = studio.methods
+      .deleteProduct()
+      .accounts({
+        authority: admin.publicKey,
+        product: productId,
+      })
+      .signers([admin]);
+    const openEscrowError = await invertPromise<AnchorError>(
+      deleteProductBuilder.rpc()
     );
-    assert.equal(buyTx.meta.err, null);
+    deepStrictEqual(openEscrowError.error.errorCode, {
+      code: "OpenEscrows",
+      number: 6038,
+    });
 
-    let product = await studio.account.merchProduct.fetch(productId);
-    deepStrictEqual(product, {
-      ...DEFAULT_PRODUCT_CONFIG,
-      id: productId,
-      currentSupply: "1",
-      linkedMasterNft: nftMint,
-      maxSupply: {
-        some: {
-          "0": "3",
-        },
-      },
-      claimsPerEdition: 2,
+    const refundBuilder = await refundOrderBuilder({
+      orderId,
+      authority: admin,
     });
+    const refundSignature = await refundBuilder.rpc();
+    await sleep(2000);
 
-    let claimMarkerAccount = await connection.getAccountInfo(
-      findClaimMarkerPda(editionMint)
+    const refundTx = await connection.getParsedTransaction(
+      refundSignature,
+      "confirmed"
+    );
+    deepStrictEqual(
+      [
+        getBalanceDelta(refundTx, user.publicKey),
+        getTokenBalanceDelta(refundTx, mint, user.publicKey),
+        getTokenBalanceDelta(refundTx, mint, admin.publicKey),
+      ],
+      [0.1 * LAMPORTS_PER_SOL, 100n * 1_000_000n, -100n * 1_000_000n],
+      "payments refunded by the seller"
     );
-    deepStrictEqual(claimMarkerAccount.data.toString("hex"), "01000000");
 
-    // multiple claims with same edition works
-    const secondClaimSignature = await buyProduct({
+    const order = await studio.account.order.fetch(orderId);
+    deepStrictEqual(order.status, { refunded: {} });
+
+    const product = await studio.account.merchProduct.fetch(productId);
+    assert.equal(product.currentSupply.toString(), "0");
+
+    // claim is given back to the edition
+    const claimMarkerAccount = await connection.getAccountInfo(
+      findClaimMarkerPda(productId, editionMint)
+    );
+    deepStrictEqual(claimMarkerAccount.data.toString("hex"), "00000000");
+
+    await buyProduct({
       productId,
       buyer: user,
       edition: editionMint,
     });
-    await sleep(2000);
+  });
 
-    const secondClaimTx = await connection.getParsedTransaction(
-      secondClaimSignature,
-      "confirmed"
-    );
-    assert.equal(secondClaimTx.meta.err, null);
+  it("buy product with linked master edition and multiple claims", async () => {
+    const { nftMint } = await createAndMintNft({
+      connection,
+      payer: admin,
 
-    product = await studio.account.merchProduct.fetch(productId);
-    deepStrictEqual(product, {
-      ...DEFAULT_PRODUCT_CONFIG,
-      id: productId,
-      currentSupply: "2",
+      name: "Master Edition NFT",
+      symbol: "TEST",
+      uri: "https://example.com/",
+      maxSupply: 1,
+    });
+
+    const { productId } = await createProduct({
+      overrides: {
+        linkedMasterNft: nftMint,
+        maxSupply: { some: [new anchor.BN(3)] },
+        claimsPerEdition: 2,
+      },
+    });
+
+    const { editionMint } = await createPrint({
+      connection,
+      payer: admin,
+      owner: user.publicKey,
+      masterEditionMint: nftMint,
+      editionNumber: 1,
+    });
+
+    const buySignature = await buyProduct({
+      productId,
+      buyer: user,
+      edition: editionMint,
+    });
+    await sleep(2000);
+
+    // less than max supply works
+    const buyTx = await connection.getParsedTransaction(
+      buySignature,
+      "confirmed"
+    );
+    assert.equal(buyTx.meta.err, null);
+
+    let product = await studio.account.merchProduct.fetch(productId);
+    deepStrictEqual(product, {
+      ...DEFAULT_PRODUCT_CONFIG,
+      id: productId,
+      currentSupply: "1",
+      orderCount: "1",
+      linkedMasterNft: nftMint,
+      maxSupply: {
+        some: {
+          "0": "3",
+        },
+      },
+      claimsPerEdition: 2,
+      maxPerWallet: 0,
+    });
+
+    let claimMarkerAccount = await connection.getAccountInfo(
+      findClaimMarkerPda(productId, editionMint)
+    );
+    deepStrictEqual(claimMarkerAccount.data.toString("hex"), "01000000");
+
+    // multiple claims with same edition works
+    const secondClaimSignature = await buyProduct({
+      productId,
+      buyer: user,
+      edition: editionMint,
+    });
+    await sleep(2000);
+
+    const secondClaimTx = await connection.getParsedTransaction(
+      secondClaimSignature,
+      "confirmed"
+    );
+    assert.equal(secondClaimTx.meta.err, null);
+
+    product = await studio.account.merchProduct.fetch(productId);
+    deepStrictEqual(product, {
+      ...DEFAULT_PRODUCT_CONFIG,
+      id: productId,
+      currentSupply: "2",
+      orderCount: "2",
       linkedMasterNft: nftMint,
       maxSupply: {
         some: {
@@ -416,10 +943,11 @@ describe("foster-studio", () => {
         },
       },
       claimsPerEdition: 2,
+      maxPerWallet: 0,
     });
 
     claimMarkerAccount = await connection.getAccountInfo(
-      findClaimMarkerPda(editionMint)
+      findClaimMarkerPda(productId, editionMint)
     );
     deepStrictEqual(claimMarkerAccount.data.toString("hex"), "02000000");
 
@@ -439,4 +967,1939 @@ describe("foster-studio", () => {
       origin: undefined,
     });
   });
+
+  it("escrowed purchase is released", async () => {
+    const { productId } = await createProduct({
+      overrides: {
+        escrow: { timeout: new anchor.BN(60 * 60) },
+      },
+    });
+
+    await buyProduct({
+      productId,
+      buyer: user,
+    });
+    await sleep(2000);
+
+    const orderId = findOrderPda(productId, user.publicKey, new anchor.BN(0));
+    let order = await studio.account.order.fetch(orderId);
+    deepStrictEqual(order.status, { escrowed: {} });
+    assert.equal(
+      order.escrowExpiresAt.sub(order.createdAt).toNumber(),
+      60 * 60
+    );
+    let product = await studio.account.merchProduct.fetch(productId);
+    assert.equal(product.openEscrows.toString(), "1");
+
+    // buyer cannot reclaim funds before the escrow expires
+    const claimBuilder = await claimOrderRefundBuilder({
+      orderId,
+      buyer: user,
+    });
+    const earlyClaimError = await invertPromise<AnchorError>(
+      claimBuilder.rpc()
+    );
+    deepStrictEqual(earlyClaimError.error, {
+      errorCode: { code: "EscrowNotExpired", number: 6013 },
+      errorMessage: "Escrow not expired",
+      comparedValues: undefined,
+      origin: undefined,
+    });
+
+    // seller marks the order fulfilled
+    const releaseBuilder = await releaseOrderBuilder({
+      orderId,
+      authority: admin,
+    });
+    const releaseSignature = await releaseBuilder.rpc();
+    await sleep(2000);
+
+    const releaseTx = await connection.getParsedTransaction(
+      releaseSignature,
+      "confirmed"
+    );
+    deepStrictEqual(
+      [
+        getBalanceDelta(releaseTx, orderId),
+        getTokenBalanceDelta(releaseTx, mint, admin.publicKey),
+      ],
+      [-0.1 * LAMPORTS_PER_SOL, 100n * 1_000_000n],
+      "escrowed payments released"
+    );
+
+    order = await studio.account.order.fetch(orderId);
+    deepStrictEqual(order.status, { released: {} });
+    product = await studio.account.merchProduct.fetch(productId);
+    assert.equal(product.openEscrows.toString(), "0");
+  });
+
+  it("escrow timeouts are validated", async () => {
+    for (const timeout of [0, -1, 366 * 24 * 60 * 60]) {
+      const invalidEscrowError = await invertPromise<AnchorError>(
+        createProduct({
+          overrides: { escrow: { timeout: new anchor.BN(timeout) } },
+        })
+      );
+      deepStrictEqual(invalidEscrowError.error.errorCode, {
+        code: "InvalidEscrow",
+        number: 6039,
+      });
+    }
+  });
+
+  it("escrowed purchase is refunded", async () => {
+    const { productId } = await createProduct({
+      overrides: {
+        escrow: { timeout: new anchor.BN(60 * 60) },
+      },
+    });
+
+    await buyProduct({
+      productId,
+      buyer: user,
+    });
+    await sleep(2000);
+
+    const orderId = findOrderPda(productId, user.publicKey, new anchor.BN(0));
+
+    // only the product authority can refund
+    const userRefundBuilder = await refundOrderBuilder({
+      orderId,
+      authority: user,
+    });
+    const userRefundError = await invertPromise<AnchorError>(
+      userRefundBuilder.rpc()
+    );
+    deepStrictEqual(userRefundError.error, {
+      errorCode: { code: "IncorrectAuthority", number: 6000 },
+      errorMessage: "Incorrect Authority",
+      comparedValues: undefined,
+      origin: undefined,
+    });
+
+    const refundBuilder = await refundOrderBuilder({
+      orderId,
+      authority: admin,
+    });
+    const refundSignature = await refundBuilder.rpc();
+    await sleep(2000);
+
+    const refundTx = await connection.getParsedTransaction(
+      refundSignature,
+      "confirmed"
+    );
+    assert.ok(getBalanceDelta(refundTx, user.publicKey) >= 0.1 * LAMPORTS_PER_SOL);
+    deepStrictEqual(
+      getTokenBalanceDelta(refundTx, mint, user.publicKey),
+      100n * 1_000_000n,
+      "escrowed tokens refunded"
+    );
+
+    const order = await studio.account.order.fetch(orderId);
+    deepStrictEqual(order.status, { refunded: {} });
+
+    const product = await studio.account.merchProduct.fetch(productId);
+    assert.equal(product.currentSupply.toString(), "0");
+    assert.equal(product.openEscrows.toString(), "0");
+
+    // refunded orders cannot be released
+    const releaseBuilder = await releaseOrderBuilder({
+      orderId,
+      authority: admin,
+    });
+    const releaseError = await invertPromise<AnchorError>(
+      releaseBuilder.rpc()
+    );
+    deepStrictEqual(releaseError.error, {
+      errorCode: { code: "InvalidOrderStatus", number: 6012 },
+      errorMessage: "Invalid order status",
+      comparedValues: undefined,
+      origin: undefined,
+    });
+
+    // once every escrow is closed, the product can be deleted
+    await deleteProductBuilder.rpc();
+  });
+
+  it("buy product with linked collection", async () => {
+    const { nftMint: collectionMint } = await createAndMintNft({
+      connection,
+      payer: admin,
+
+      name: "Collection NFT",
+      symbol: "TEST",
+      uri: "https://example.com/",
+      maxSupply: 0,
+      isCollection: true,
+    });
+
+    const { nftMint: verifiedNft } = await createAndMintNft({
+      connection,
+      payer: admin,
+      owner: user.publicKey,
+
+      name: "Collection Item",
+      symbol: "TEST",
+      uri: "https://example.com/",
+      maxSupply: 0,
+      collection: collectionMint,
+    });
+    await verifyCollection({
+      connection,
+      collectionAuthority: admin,
+      mint: verifiedNft,
+      collectionMint,
+    });
+
+    const { nftMint: unverifiedNft } = await createAndMintNft({
+      connection,
+      payer: admin,
+      owner: user.publicKey,
+
+      name: "Unverified Collection Item",
+      symbol: "TEST",
+      uri: "https://example.com/",
+      maxSupply: 0,
+      collection: collectionMint,
+    });
+
+    const { productId } = await createProduct({
+      overrides: {
+        linkedCollection: collectionMint,
+        claimsPerEdition: 1,
+      },
+    });
+
+    // attempting to buy without an nft fails
+    const noNftBuyError = await invertPromise<AnchorError>(
+      buyProduct({
+        productId,
+        buyer: user,
+      })
+    );
+    deepStrictEqual(noNftBuyError.error, {
+      errorCode: { code: "MissingCollectionNft", number: 6017 },
+      errorMessage: "Missing collection nft",
+      comparedValues: undefined,
+      origin: undefined,
+    });
+
+    // nfts that are not verified in the collection are rejected
+    const unverifiedBuyError = await invertPromise<AnchorError>(
+      buyProduct({
+        productId,
+        buyer: user,
+        nft: unverifiedNft,
+      })
+    );
+    deepStrictEqual(unverifiedBuyError.error.errorCode, {
+      code: "AccountMismatch",
+      number: 6009,
+    });
+
+    // nfts must be held by the buyer
+    const notHolderBuyError = await invertPromise<AnchorError>(
+      buyProduct({
+        productId,
+        buyer: admin,
+        nft: verifiedNft,
+        nftTokenAccount: getAssociatedTokenAddressSync(
+          verifiedNft,
+          user.publicKey
+        ),
+      })
+    );
+    deepStrictEqual(notHolderBuyError.error.errorCode, {
+      code: "AccountMismatch",
+      number: 6009,
+    });
+
+    await buyProduct({
+      productId,
+      buyer: user,
+      nft: verifiedNft,
+    });
+    await sleep(2000);
+
+    const claimMarkerAccount = await connection.getAccountInfo(
+      findNftClaimMarkerPda(productId, verifiedNft)
+    );
+    deepStrictEqual(claimMarkerAccount.data.toString("hex"), "01000000");
+
+    // attempting to exceed claim allotment fails
+    const exceededClaimsBuyError = await invertPromise<AnchorError>(
+      buyProduct({
+        productId,
+        buyer: user,
+        nft: verifiedNft,
+      })
+    );
+    deepStrictEqual(exceededClaimsBuyError.error.errorCode, {
+      code: "NoMoreClaims",
+      number: 6008,
+    });
+  });
+
+  it("buy product with token-2022 transfer fees", async () => {
+    const feeRecipient = getAssociatedTokenAddressSync(
+      feeMint,
+      admin.publicKey,
+      false,
+      TOKEN_2022_PROGRAM_ID
+    );
+    const feePayment = {
+      tag: "fee token amount",
+      mint: feeMint,
+      // 100 tokens
+      amount: new anchor.BN(100 * 1_000_000),
+      recipient: feeRecipient,
+      option: 0,
+      splits: [],
+      auction: null,
+      curve: null,
+    };
+
+    // net payments add the 1% fee on top, the recipient receives the full amount
+    const { productId: netProductId } = await createProduct({
+      overrides: {
+        payments: [{ ...feePayment, transferFeeMode: { net: {} } }],
+      },
+    });
+    const netBuySignature = await buyProduct({
+      productId: netProductId,
+      buyer: user,
+    });
+    await sleep(2000);
+
+    const netBuyTx = await connection.getParsedTransaction(
+      netBuySignature,
+      "confirmed"
+    );
+    deepStrictEqual(
+      [
+        getTokenBalanceDelta(
+          netBuyTx,
+          feeMint,
+          user.publicKey,
+          TOKEN_2022_PROGRAM_ID
+        ),
+        getTokenBalanceDelta(
+          netBuyTx,
+          feeMint,
+          admin.publicKey,
+          TOKEN_2022_PROGRAM_ID
+        ),
+      ],
+      [-101n * 1_000_000n, 100n * 1_000_000n],
+      "net token payments processed"
+    );
+
+    // gross payments deduct the fee from the amount received
+    const { productId: grossProductId } = await createProduct({
+      overrides: {
+        payments: [{ ...feePayment, transferFeeMode: { gross: {} } }],
+      },
+    });
+    const grossBuySignature = await buyProduct({
+      productId: grossProductId,
+      buyer: user,
+    });
+    await sleep(2000);
+
+    const grossBuyTx = await connection.getParsedTransaction(
+      grossBuySignature,
+      "confirmed"
+    );
+    deepStrictEqual(
+      [
+        getTokenBalanceDelta(
+          grossBuyTx,
+          feeMint,
+          user.publicKey,
+          TOKEN_2022_PROGRAM_ID
+        ),
+        getTokenBalanceDelta(
+          grossBuyTx,
+          feeMint,
+          admin.publicKey,
+          TOKEN_2022_PROGRAM_ID
+        ),
+      ],
+      [-100n * 1_000_000n, 99n * 1_000_000n],
+      "gross token payments processed"
+    );
+
+    // token-2022 mints require the token-2022 program
+    const buyBuilder = await buyProductBuilder({
+      productId: netProductId,
+      buyer: user,
+    });
+    const missingProgramError = await invertPromise<AnchorError>(
+      buyBuilder.accountsPartial({ token2022Program: null }).rpc()
+    );
+    deepStrictEqual(missingProgramError.error.errorCode, {
+      code: "MissingTokenProgram",
+      number: 6018,
+    });
+  });
+
+  it("escrowed token-2022 payments keep their transfer fee mode", async () => {
+    const feePayment = {
+      tag: "fee token amount",
+      mint: feeMint,
+      // 100 tokens
+      amount: new anchor.BN(100 * 1_000_000),
+      recipient: getAssociatedTokenAddressSync(
+        feeMint,
+        admin.publicKey,
+        false,
+        TOKEN_2022_PROGRAM_ID
+      ),
+      option: 0,
+      splits: [],
+      auction: null,
+      curve: null,
+    };
+    const escrow = { timeout: new anchor.BN(60 * 60) };
+
+    // net payments also cover the fee of the release, the recipient receives the full amount
+    const { productId: netProductId } = await createProduct({
+      overrides: {
+        payments: [{ ...feePayment, transferFeeMode: { net: {} } }],
+        escrow,
+      },
+    });
+    await buyProduct({ productId: netProductId, buyer: user });
+    await sleep(2000);
+
+    const netOrderId = findOrderPda(
+      netProductId,
+      user.publicKey,
+      new anchor.BN(0)
+    );
+    const releaseBuilder = await releaseOrderBuilder({
+      orderId: netOrderId,
+      authority: admin,
+    });
+    const releaseSignature = await releaseBuilder.rpc();
+    await sleep(2000);
+
+    const releaseTx = await connection.getParsedTransaction(
+      releaseSignature,
+      "confirmed"
+    );
+    deepStrictEqual(
+      getTokenBalanceDelta(
+        releaseTx,
+        feeMint,
+        admin.publicKey,
+        TOKEN_2022_PROGRAM_ID
+      ),
+      100n * 1_000_000n,
+      "net escrowed payment released"
+    );
+
+    // gross payments escrow what the order received, the refund pays the fee again
+    const { productId: grossProductId } = await createProduct({
+      overrides: {
+        payments: [{ ...feePayment, transferFeeMode: { gross: {} } }],
+        escrow,
+      },
+    });
+    await buyProduct({ productId: grossProductId, buyer: user });
+    await sleep(2000);
+
+    const grossOrderId = findOrderPda(
+      grossProductId,
+      user.publicKey,
+      new anchor.BN(0)
+    );
+    const order = await studio.account.order.fetch(grossOrderId);
+    assert.equal(order.payments[0].escrowedAmount.toString(), "99000000");
+
+    const refundBuilder = await refundOrderBuilder({
+      orderId: grossOrderId,
+      authority: admin,
+    });
+    const refundSignature = await refundBuilder.rpc();
+    await sleep(2000);
+
+    const refundTx = await connection.getParsedTransaction(
+      refundSignature,
+      "confirmed"
+    );
+    deepStrictEqual(
+      getTokenBalanceDelta(
+        refundTx,
+        feeMint,
+        user.publicKey,
+        TOKEN_2022_PROGRAM_ID
+      ),
+      98_010_000n,
+      "gross escrowed payment refunded"
+    );
+
+    // withheld fees were harvested so the vaults could be closed
+    for (const orderId of [netOrderId, grossOrderId]) {
+      const vault = await connection.getAccountInfo(
+        getAssociatedTokenAddressSync(
+          feeMint,
+          orderId,
+          true,
+          TOKEN_2022_PROGRAM_ID
+        )
+      );
+      assert.equal(vault, null);
+    }
+  });
+
+  it("buy product with payment options", async () => {
+    const [solPayment, tokenPayment] = DEFAULT_PRODUCT_CONFIG.payments;
+    const { productId } = await createProduct({
+      overrides: {
+        payments: [
+          { ...solPayment, option: 0 },
+          { ...tokenPayment, option: 1 },
+        ],
+      },
+    });
+
+    // only the payments of the selected option are charged
+    const buySignature = await buyProduct({
+      productId,
+      buyer: user,
+      paymentOption: 1,
+    });
+    await sleep(2000);
+
+    const buyTx = await connection.getParsedTransaction(
+      buySignature,
+      "confirmed"
+    );
+    deepStrictEqual(
+      [
+        getTokenBalanceDelta(buyTx, mint, user.publicKey),
+        getTokenBalanceDelta(buyTx, mint, admin.publicKey),
+      ],
+      [-100n * 1_000_000n, 100n * 1_000_000n],
+      "token option charged"
+    );
+
+    const order = await studio.account.order.fetch(
+      findOrderPda(productId, user.publicKey, new anchor.BN(0))
+    );
+    deepStrictEqual(
+      order.payments.map(({ mint }) => mint),
+      [mint],
+      "only the selected option is recorded"
+    );
+
+    // options without payments are rejected
+    const invalidOptionError = await invertPromise<AnchorError>(
+      buyProduct({
+        productId,
+        buyer: user,
+        paymentOption: 2,
+      })
+    );
+    deepStrictEqual(invalidOptionError.error.errorCode, {
+      code: "InvalidPaymentOption",
+      number: 6019,
+    });
+  });
+
+  it("buy product with payment splits", async () => {
+    const artist = Keypair.generate().publicKey;
+    const designer = Keypair.generate().publicKey;
+    const [, tokenPayment] = DEFAULT_PRODUCT_CONFIG.payments;
+    const splitPayment = {
+      ...tokenPayment,
+      // 100 tokens and 1 base unit, so the shares do not divide evenly
+      amount: new anchor.BN(100_000_001),
+      splits: [
+        {
+          recipient: getAssociatedTokenAddressSync(mint, admin.publicKey),
+          shareBps: 5000,
+        },
+        {
+          recipient: getAssociatedTokenAddressSync(mint, artist),
+          shareBps: 3333,
+        },
+        {
+          recipient: getAssociatedTokenAddressSync(mint, designer),
+          shareBps: 1667,
+        },
+      ],
+    };
+
+    // shares must sum to 100%
+    const invalidSplitsError = await invertPromise<AnchorError>(
+      createProduct({
+        overrides: {
+          payments: [
+            {
+              ...splitPayment,
+              splits: splitPayment.splits.slice(0, 2),
+            },
+          ],
+        },
+      })
+    );
+    deepStrictEqual(invalidSplitsError.error.errorCode, {
+      code: "InvalidPaymentSplits",
+      number: 6020,
+    });
+
+    const { productId } = await createProduct({
+      overrides: { payments: [splitPayment] },
+    });
+    const buyBuilder = await buyProductBuilder({
+      productId,
+      buyer: user,
+    });
+    const buySignature = await buyBuilder
+      .preInstructions(
+        [artist, designer].map((owner) =>
+          createAssociatedTokenAccountIdempotentInstruction(
+            user.publicKey,
+            getAssociatedTokenAddressSync(mint, owner),
+            owner,
+            mint
+          )
+        )
+      )
+      .rpc();
+    await sleep(2000);
+
+    // the rounding remainder goes to the first split
+    const buyTx = await connection.getParsedTransaction(
+      buySignature,
+      "confirmed"
+    );
+    deepStrictEqual(
+      [
+        getTokenBalanceDelta(buyTx, mint, user.publicKey),
+        getTokenBalanceDelta(buyTx, mint, admin.publicKey),
+        getTokenBalanceDelta(buyTx, mint, artist),
+        getTokenBalanceDelta(buyTx, mint, designer),
+      ],
+      [-100_000_001n, 50_000_001n, 33_330_000n, 16_670_000n],
+      "split payments processed"
+    );
+
+    const order = await studio.account.order.fetch(
+      findOrderPda(productId, user.publicKey, new anchor.BN(0))
+    );
+    deepStrictEqual(
+      order.payments.map(({ recipient, amount }) => [recipient, amount]),
+      splitPayment.splits.map(({ recipient }, i) => [
+        recipient,
+        ["50000001", "33330000", "16670000"][i],
+      ]),
+      "one order payment is recorded per split"
+    );
+  });
+
+  it("platform fees are charged", async () => {
+    const platform = Keypair.generate().publicKey;
+    const platformAta = getAssociatedTokenAddressSync(mint, platform);
+
+    // only the admin can update the config
+    const notAdminError = await invertPromise<AnchorError>(
+      studio.methods
+        .updateConfig({
+          admin: null,
+          platformFeeBps: 250,
+          feeRecipients: null,
+          paused: null,
+        })
+        .accounts({ admin: user.publicKey })
+        .signers([user])
+        .rpc()
+    );
+    deepStrictEqual(notAdminError.error.errorCode, {
+      code: "IncorrectAuthority",
+      number: 6000,
+    });
+
+    // fees are capped at 100%
+    const invalidFeeError = await invertPromise<AnchorError>(
+      studio.methods
+        .updateConfig({
+          admin: null,
+          platformFeeBps: 10_001,
+          feeRecipients: null,
+          paused: null,
+        })
+        .accounts({ admin: admin.publicKey })
+        .signers([admin])
+        .rpc()
+    );
+    deepStrictEqual(invalidFeeError.error.errorCode, {
+      code: "InvalidPlatformFee",
+      number: 6021,
+    });
+
+    // 2.5% fee on token payments
+    await studio.methods
+      .updateConfig({
+        admin: null,
+        platformFeeBps: 250,
+        feeRecipients: [{ mint, recipient: platformAta }],
+        paused: null,
+      })
+      .accounts({ admin: admin.publicKey })
+      .signers([admin])
+      .rpc();
+
+    try {
+      const { productId } = await createProduct();
+      const buyBuilder = await buyProductBuilder({
+        productId,
+        buyer: user,
+      });
+      const buySignature = await buyBuilder
+        .preInstructions([
+          createAssociatedTokenAccountIdempotentInstruction(
+            user.publicKey,
+            platformAta,
+            platform,
+            mint
+          ),
+        ])
+        .rpc();
+      await sleep(2000);
+
+      const buyTx = await connection.getParsedTransaction(
+        buySignature,
+        "confirmed"
+      );
+      deepStrictEqual(
+        [
+          getTokenBalanceDelta(buyTx, mint, user.publicKey),
+          getTokenBalanceDelta(buyTx, mint, admin.publicKey),
+          getTokenBalanceDelta(buyTx, mint, platform),
+        ],
+        [-102_500_000n, 100_000_000n, 2_500_000n],
+        "platform fee charged on top of the payment"
+      );
+      deepStrictEqual(
+        [getBalanceDelta(buyTx, admin.publicKey)],
+        [0.1 * LAMPORTS_PER_SOL],
+        "no fee for mints without a fee recipient"
+      );
+
+      const order = await studio.account.order.fetch(
+        findOrderPda(productId, user.publicKey, new anchor.BN(0))
+      );
+      deepStrictEqual(
+        order.payments.map(({ platformFee, feeRecipient }) => [
+          platformFee,
+          feeRecipient,
+        ]),
+        [
+          ["0", PublicKey.default],
+          ["2500000", platformAta],
+        ]
+      );
+    } finally {
+      await studio.methods
+        .updateConfig({
+          admin: null,
+          platformFeeBps: 0,
+          feeRecipients: [],
+          paused: null,
+        })
+        .accounts({ admin: admin.publicKey })
+        .signers([admin])
+        .rpc();
+    }
+  });
+
+  it("paused products and program cannot be bought", async () => {
+    const id = Keypair.generate();
+    await createProduct({ id, overrides: { paused: true } });
+
+    const productPausedError = await invertPromise<AnchorError>(
+      buyProduct({ productId: id.publicKey, buyer: user })
+    );
+    deepStrictEqual(productPausedError.error.errorCode, {
+      code: "ProductPaused",
+      number: 6023,
+    });
+
+    // sellers resume sales through configure product
+    await createProduct({ id, overrides: { paused: false } });
+    await buyProduct({ productId: id.publicKey, buyer: user });
+
+    // the admin pauses every product
+    await studio.methods
+      .updateConfig({
+        admin: null,
+        platformFeeBps: null,
+        feeRecipients: null,
+        paused: true,
+      })
+      .accounts({ admin: admin.publicKey })
+      .signers([admin])
+      .rpc();
+
+    try {
+      const programPausedError = await invertPromise<AnchorError>(
+        buyProduct({ productId: id.publicKey, buyer: user })
+      );
+      deepStrictEqual(programPausedError.error.errorCode, {
+        code: "ProgramPaused",
+        number: 6022,
+      });
+    } finally {
+      await studio.methods
+        .updateConfig({
+          admin: null,
+          platformFeeBps: null,
+          feeRecipients: null,
+          paused: false,
+        })
+        .accounts({ admin: admin.publicKey })
+        .signers([admin])
+        .rpc();
+    }
+  });
+
+  it("only approved affiliates are paid", async () => {
+    const referrer = Keypair.generate();
+    const { productId } = await createProduct();
+
+    // unregistered referrers are rejected
+    const unregisteredError = await invertPromise<AnchorError>(
+      buyProduct({
+        productId,
+        buyer: user,
+        referrer: referrer.publicKey,
+        affiliate: null,
+      })
+    );
+    deepStrictEqual(unregisteredError.error.errorCode, {
+      code: "AffiliateNotApproved",
+      number: 6024,
+    });
+
+    // registered with the product, but not approved by the seller
+    const affiliate = await registerAffiliate({
+      wallet: referrer,
+      scope: productId,
+      seller: null,
+    });
+    const unapprovedError = await invertPromise<AnchorError>(
+      buyProduct({
+        productId,
+        buyer: user,
+        referrer: referrer.publicKey,
+        affiliate,
+      })
+    );
+    deepStrictEqual(unapprovedError.error.errorCode, {
+      code: "AffiliateNotApproved",
+      number: 6024,
+    });
+
+    // affiliates cannot approve themselves
+    const selfApprovalError = await invertPromise<AnchorError>(
+      studio.methods
+        .updateAffiliate({ commissionBps: null, active: null, approved: true })
+        .accountsPartial({
+          authority: referrer.publicKey,
+          affiliate,
+          product: productId,
+        })
+        .signers([referrer])
+        .rpc()
+    );
+    deepStrictEqual(selfApprovalError.error.errorCode, {
+      code: "IncorrectAuthority",
+      number: 6000,
+    });
+
+    // the seller approves the affiliate with a 5% commission
+    await studio.methods
+      .updateAffiliate({ commissionBps: 500, active: null, approved: true })
+      .accountsPartial({
+        authority: admin.publicKey,
+        affiliate,
+        product: productId,
+      })
+      .signers([admin])
+      .rpc();
+
+    const buyBuilder = await buyProductBuilder({
+      productId,
+      buyer: user,
+      referrer: referrer.publicKey,
+      affiliate,
+    });
+    const buySignature = await buyBuilder
+      .preInstructions([
+        createAssociatedTokenAccountIdempotentInstruction(
+          user.publicKey,
+          getAssociatedTokenAddressSync(mint, referrer.publicKey),
+          referrer.publicKey,
+          mint
+        ),
+      ])
+      .rpc();
+    await sleep(2000);
+
+    const buyTx = await connection.getParsedTransaction(
+      buySignature,
+      "confirmed"
+    );
+    deepStrictEqual(
+      [
+        getBalanceDelta(buyTx, referrer.publicKey),
+        getTokenBalanceDelta(buyTx, mint, referrer.publicKey),
+      ],
+      [0.005 * LAMPORTS_PER_SOL, 5n * 1_000_000n],
+      "negotiated commission paid"
+    );
+
+    // inactive affiliates are not paid
+    await studio.methods
+      .updateAffiliate({ commissionBps: null, active: false, approved: null })
+      .accountsPartial({
+        authority: referrer.publicKey,
+        affiliate,
+        product: productId,
+      })
+      .signers([referrer])
+      .rpc();
+    const inactiveError = await invertPromise<AnchorError>(
+      buyProduct({
+        productId,
+        buyer: user,
+        referrer: referrer.publicKey,
+        affiliate,
+      })
+    );
+    deepStrictEqual(inactiveError.error.errorCode, {
+      code: "AffiliateInactive",
+      number: 6025,
+    });
+  });
+
+  it("referral commission taken from the seller", async () => {
+    // the commission cannot exceed the price
+    const invalidCommissionError = await invertPromise<AnchorError>(
+      createProduct({
+        overrides: {
+          commissionMode: { fromSeller: {} },
+          affiliateCommissionBps: 10001,
+        },
+      })
+    );
+    deepStrictEqual(invalidCommissionError.error.errorCode, {
+      code: "InvalidCommission",
+      number: 6026,
+    });
+
+    const referrer = Keypair.generate();
+    const { productId } = await createProduct({
+      overrides: { commissionMode: { fromSeller: {} } },
+    });
+    await registerAffiliate({ wallet: referrer, scope: admin.publicKey });
+
+    const buyBuilder = await buyProductBuilder({
+      productId,
+      buyer: user,
+      referrer: referrer.publicKey,
+    });
+    const buySignature = await buyBuilder
+      .preInstructions([
+        createAssociatedTokenAccountIdempotentInstruction(
+          user.publicKey,
+          getAssociatedTokenAddressSync(mint, referrer.publicKey),
+          referrer.publicKey,
+          mint
+        ),
+      ])
+      .rpc();
+    await sleep(2000);
+
+    // the buyer pays the listed price, the seller pays the commission
+    const buyTx = await connection.getParsedTransaction(
+      buySignature,
+      "confirmed"
+    );
+    deepStrictEqual(
+      [
+        getBalanceDelta(buyTx, admin.publicKey),
+        getBalanceDelta(buyTx, referrer.publicKey),
+      ],
+      [0.099 * LAMPORTS_PER_SOL, 0.001 * LAMPORTS_PER_SOL],
+      "sol commission taken from the seller"
+    );
+    deepStrictEqual(
+      [
+        getTokenBalanceDelta(buyTx, mint, user.publicKey),
+        getTokenBalanceDelta(buyTx, mint, admin.publicKey),
+        getTokenBalanceDelta(buyTx, mint, referrer.publicKey),
+      ],
+      [-100n * 1_000_000n, 99n * 1_000_000n, 1n * 1_000_000n],
+      "token commission taken from the seller"
+    );
+  });
+
+  it("sales stats are recorded", async () => {
+    const referrer = Keypair.generate();
+    const { productId } = await createProduct();
+    await registerAffiliate({ wallet: referrer, scope: admin.publicKey });
+
+    const buyBuilder = await buyProductBuilder({
+      productId,
+      buyer: user,
+      referrer: referrer.publicKey,
+      quantity: 2,
+    });
+    await buyBuilder
+      .preInstructions([
+        createAssociatedTokenAccountIdempotentInstruction(
+          user.publicKey,
+          getAssociatedTokenAddressSync(mint, referrer.publicKey),
+          referrer.publicKey,
+          mint
+        ),
+      ])
+      .rpc();
+    await buyProduct({ productId, buyer: user });
+    await sleep(2000);
+
+    const productStats = await studio.account.salesStats.fetch(
+      findProductStatsPda(productId)
+    );
+    assert.ok(productStats.lastSaleAt.toNumber() > 0);
+    deepStrictEqual(
+      { ...productStats, lastSaleAt: undefined },
+      {
+        subject: productId,
+        salesCount: "2",
+        unitsSold: "3",
+        volume: [
+          {
+            mint: PublicKey.default,
+            amount: (0.3 * LAMPORTS_PER_SOL).toString(),
+          },
+          { mint, amount: (300n * 1_000_000n).toString() },
+        ],
+        lastSaleAt: undefined,
+      }
+    );
+
+    // only the referred sale is counted for the referrer
+    const referrerStats = await studio.account.salesStats.fetch(
+      findReferrerStatsPda(referrer.publicKey)
+    );
+    deepStrictEqual(
+      { ...referrerStats, lastSaleAt: undefined },
+      {
+        subject: referrer.publicKey,
+        salesCount: "1",
+        unitsSold: "2",
+        volume: [
+          {
+            mint: PublicKey.default,
+            amount: (0.2 * LAMPORTS_PER_SOL).toString(),
+          },
+          { mint, amount: (200n * 1_000_000n).toString() },
+        ],
+        lastSaleAt: undefined,
+      }
+    );
+  });
+
+  it("pre-funded stats do not block sales", async () => {
+    const referrer = Keypair.generate();
+    const { productId } = await createProduct();
+    await registerAffiliate({ wallet: referrer, scope: admin.publicKey });
+
+    // anyone can send lamports to the stats pdas before the first sale
+    await sendAndConfirmTransaction(
+      connection,
+      new Transaction().add(
+        ...[
+          findProductStatsPda(productId),
+          findReferrerStatsPda(referrer.publicKey),
+        ].map((toPubkey) =>
+          SystemProgram.transfer({
+            fromPubkey: admin.publicKey,
+            toPubkey,
+            lamports: 1,
+          })
+        )
+      ),
+      [admin]
+    );
+
+    const buyBuilder = await buyProductBuilder({
+      productId,
+      buyer: user,
+      referrer: referrer.publicKey,
+    });
+    await buyBuilder
+      .preInstructions([
+        createAssociatedTokenAccountIdempotentInstruction(
+          user.publicKey,
+          getAssociatedTokenAddressSync(mint, referrer.publicKey),
+          referrer.publicKey,
+          mint
+        ),
+      ])
+      .rpc();
+    await sleep(2000);
+
+    const productStats = await studio.account.salesStats.fetch(
+      findProductStatsPda(productId)
+    );
+    assert.equal(productStats.salesCount.toNumber(), 1);
+    const referrerStats = await studio.account.salesStats.fetch(
+      findReferrerStatsPda(referrer.publicKey)
+    );
+    assert.equal(referrerStats.salesCount.toNumber(), 1);
+  });
+
+  it("referrers cannot collect their own commission", async () => {
+    const seller = Keypair.generate().publicKey;
+    const [solPayment, tokenPayment] = DEFAULT_PRODUCT_CONFIG.payments;
+    const { productId } = await createProduct({
+      overrides: {
+        payments: [{ ...solPayment, recipient: seller }, tokenPayment],
+      },
+    });
+
+    // buyers, sellers and payment recipients are rejected as referrers
+    for (const referrer of [user.publicKey, admin.publicKey, seller]) {
+      const referrerError = await invertPromise<AnchorError>(
+        buyProduct({
+          productId,
+          buyer: user,
+          referrer,
+          affiliate: null,
+        })
+      );
+      deepStrictEqual(referrerError.error.errorCode, {
+        code: "InvalidReferrer",
+        number: 6027,
+      });
+    }
+  });
+
+  it("buy product with max per wallet", async () => {
+    const { productId } = await createProduct({
+      overrides: { maxPerWallet: 2 },
+    });
+
+    await buyProduct({ productId, buyer: user, quantity: 2 });
+    await sleep(2000);
+
+    const purchaseMarkerAccount = await connection.getAccountInfo(
+      findPurchaseMarkerPda(productId, user.publicKey)
+    );
+    deepStrictEqual(purchaseMarkerAccount.data.toString("hex"), "02000000");
+
+    // attempting to exceed the wallet limit fails
+    const exceededLimitError = await invertPromise<AnchorError>(
+      buyProduct({ productId, buyer: user })
+    );
+    deepStrictEqual(exceededLimitError.error.errorCode, {
+      code: "NoMoreWalletPurchases",
+      number: 6028,
+    });
+
+    // other wallets have their own limit, even if their marker was pre-funded
+    await sendAndConfirmTransaction(
+      connection,
+      new Transaction().add(
+        SystemProgram.transfer({
+          fromPubkey: user.publicKey,
+          toPubkey: findPurchaseMarkerPda(productId, admin.publicKey),
+          lamports: 1,
+        })
+      ),
+      [user]
+    );
+    await buyProduct({ productId, buyer: admin, quantity: 2 });
+
+    // refunded units no longer count against the wallet limit
+    const refundBuilder = await refundOrderBuilder({
+      orderId: findOrderPda(productId, user.publicKey, new anchor.BN(0)),
+      authority: admin,
+    });
+    await refundBuilder.rpc();
+    await buyProduct({ productId, buyer: user });
+    await sleep(2000);
+
+    const refundedMarkerAccount = await connection.getAccountInfo(
+      findPurchaseMarkerPda(productId, user.publicKey)
+    );
+    deepStrictEqual(refundedMarkerAccount.data.toString("hex"), "01000000");
+  });
+
+  it("claims are scoped per product unless pooled", async () => {
+    const { nftMint } = await createAndMintNft({
+      connection,
+      payer: admin,
+
+      name: "Membership NFT",
+      symbol: "TEST",
+      uri: "https://example.com/",
+      maxSupply: 1,
+    });
+    const { editionMint } = await createPrint({
+      connection,
+      payer: admin,
+      owner: user.publicKey,
+      masterEditionMint: nftMint,
+      editionNumber: 1,
+    });
+
+    const { productId: firstProductId } = await createProduct({
+      overrides: { linkedMasterNft: nftMint, claimsPerEdition: 1 },
+    });
+    const { productId: secondProductId } = await createProduct({
+      overrides: { linkedMasterNft: nftMint, claimsPerEdition: 1 },
+    });
+
+    // the same edition can claim each product
+    await buyProduct({
+      productId: firstProductId,
+      buyer: user,
+      edition: editionMint,
+    });
+    await buyProduct({
+      productId: secondProductId,
+      buyer: user,
+      edition: editionMint,
+    });
+    await sleep(2000);
+
+    for (const productId of [firstProductId, secondProductId]) {
+      const claimMarkerAccount = await connection.getAccountInfo(
+        findClaimMarkerPda(productId, editionMint)
+      );
+      deepStrictEqual(claimMarkerAccount.data.toString("hex"), "01000000");
+    }
+
+    // products in the same claim pool share the allowance
+    const claimPool = Keypair.generate().publicKey;
+    const pooledIds = [Keypair.generate(), Keypair.generate()];
+    const pooledProductIds = [];
+    for (const id of pooledIds) {
+      const { productId } = await createProduct({
+        id,
+        overrides: {
+          linkedMasterNft: nftMint,
+          claimsPerEdition: 1,
+          claimPool,
+        },
+      });
+      pooledProductIds.push(productId);
+    }
+
+    await buyProduct({
+      productId: pooledProductIds[0],
+      buyer: user,
+      edition: editionMint,
+    });
+    await sleep(2000);
+
+    const pooledProduct = await studio.account.merchProduct.fetch(
+      pooledProductIds[0]
+    );
+    deepStrictEqual(
+      pooledProduct.claimPoolScope,
+      new PublicKey(
+        createHash("sha256")
+          .update(admin.publicKey.toBuffer())
+          .update(claimPool.toBuffer())
+          .digest()
+      )
+    );
+    const claimMarkerAccount = await connection.getAccountInfo(
+      findClaimMarkerPda(
+        getClaimScope(pooledProductIds[0], pooledProduct),
+        editionMint
+      )
+    );
+    deepStrictEqual(claimMarkerAccount.data.toString("hex"), "01000000");
+
+    const pooledClaimError = await invertPromise<AnchorError>(
+      buyProduct({
+        productId: pooledProductIds[1],
+        buyer: user,
+        edition: editionMint,
+      })
+    );
+    deepStrictEqual(pooledClaimError.error.errorCode, {
+      code: "NoMoreClaims",
+      number: 6008,
+    });
+
+    // transferring the product keeps its pooled claims
+    await createProduct({
+      id: pooledIds[1],
+      overrides: {
+        authority: user.publicKey,
+        linkedMasterNft: nftMint,
+        claimsPerEdition: 1,
+        claimPool,
+      },
+    });
+    const transferredClaimError = await invertPromise<AnchorError>(
+      buyProduct({
+        productId: pooledProductIds[1],
+        buyer: user,
+        edition: editionMint,
+      })
+    );
+    deepStrictEqual(transferredClaimError.error.errorCode, {
+      code: "NoMoreClaims",
+      number: 6008,
+    });
+
+    // another seller's product cannot join the pool
+    const { productId: otherSellerProductId } = await createProduct({
+      payer: user,
+      overrides: {
+        authority: user.publicKey,
+        linkedMasterNft: nftMint,
+        claimsPerEdition: 1,
+        claimPool,
+      },
+    });
+    await buyProduct({
+      productId: otherSellerProductId,
+      buyer: user,
+      edition: editionMint,
+    });
+
+    // new claim markers start from the legacy marker, which must be passed
+    const { productId: newProductId } = await createProduct({
+      overrides: { linkedMasterNft: nftMint, claimsPerEdition: 1 },
+    });
+    const noLegacyMarkerBuilder = await buyProductBuilder({
+      productId: newProductId,
+      buyer: user,
+      edition: editionMint,
+    });
+    const noLegacyMarkerError = await invertPromise<AnchorError>(
+      noLegacyMarkerBuilder.accountsPartial({ legacyClaimMarker: null }).rpc()
+    );
+    deepStrictEqual(noLegacyMarkerError.error.errorCode, {
+      code: "MissingClaimMarker",
+      number: 6007,
+    });
+  });
+
+  it("dutch auction prices decline with rebates", async () => {
+    const id = Keypair.generate();
+    const [solPayment] = DEFAULT_PRODUCT_CONFIG.payments;
+    const auctionConfig = {
+      // two price steps have passed
+      saleStartAt: unixTimestamp(-2 * 60 * 60 - 10 * 60),
+      saleEndAt: unixTimestamp(20),
+      escrow: { timeout: new anchor.BN(60 * 60) },
+      auctionRebate: true,
+    };
+    const auctionPayment = {
+      ...solPayment,
+      auction: {
+        floorAmount: sol(0.05),
+        interval: new anchor.BN(60 * 60),
+        step: sol(0.01),
+      },
+    };
+
+    // auctions need a sale start, rebates need escrow and a sale end
+    const invalidAuctionError = await invertPromise<AnchorError>(
+      createProduct({
+        overrides: { payments: [auctionPayment], auctionRebate: true },
+      })
+    );
+    deepStrictEqual(invalidAuctionError.error.errorCode, {
+      code: "InvalidAuction",
+      number: 6030,
+    });
+
+    const { productId } = await createProduct({
+      id,
+      overrides: { ...auctionConfig, payments: [auctionPayment] },
+    });
+
+    await buyProduct({ productId, buyer: user });
+    await sleep(2000);
+
+    const orderId = findOrderPda(productId, user.publicKey, new anchor.BN(0));
+    const order = await studio.account.order.fetch(orderId);
+    deepStrictEqual(order.payments[0].unitPrice, sol(0.08));
+
+    // steeper decline, the last sale clears lower
+    await createProduct({
+      id,
+      overrides: {
+        ...auctionConfig,
+        payments: [
+          {
+            ...auctionPayment,
+            auction: { ...auctionPayment.auction, step: sol(0.02) },
+          },
+        ],
+      },
+    });
+    await buyProduct({ productId, buyer: admin });
+    await sleep(2000);
+
+    // rebates are only known once the auction is over
+    const earlyReleaseError = await invertPromise<AnchorError>(
+      (await releaseOrderBuilder({ orderId, authority: admin })).rpc()
+    );
+    deepStrictEqual(earlyReleaseError.error.errorCode, {
+      code: "AuctionNotEnded",
+      number: 6031,
+    });
+
+    await sleep(20_000);
+    const releaseSignature = await (
+      await releaseOrderBuilder({ orderId, authority: admin })
+    ).rpc();
+    await sleep(2000);
+
+    const releaseTx = await connection.getParsedTransaction(
+      releaseSignature,
+      "confirmed"
+    );
+    assert.equal(releaseTx.meta.err, null);
+
+    // the buyer gets back the difference with the clearing price
+    assert.equal(
+      getBalanceDelta(releaseTx, user.publicKey),
+      0.02 * LAMPORTS_PER_SOL
+    );
+  });
+
+  it("auction rebates only apply to auctioned payments", async () => {
+    const [solPayment, tokenPayment] = DEFAULT_PRODUCT_CONFIG.payments;
+    const auctionPayment = {
+      ...solPayment,
+      auction: {
+        floorAmount: sol(0.05),
+        interval: new anchor.BN(60 * 60),
+        step: sol(0.01),
+      },
+    };
+    const curvePayment = {
+      ...tokenPayment,
+      curve: {
+        linear: {
+          tierSize: new anchor.BN(1),
+          increment: new anchor.BN(10 * 1_000_000),
+        },
+      },
+    };
+    const auctionConfig = {
+      saleStartAt: unixTimestamp(-60),
+      saleEndAt: unixTimestamp(20),
+      escrow: { timeout: new anchor.BN(60 * 60) },
+      auctionRebate: true,
+    };
+
+    // rebates need an auctioned payment
+    const noAuctionError = await invertPromise<AnchorError>(
+      createProduct({
+        overrides: { ...auctionConfig, payments: [curvePayment] },
+      })
+    );
+    deepStrictEqual(noAuctionError.error.errorCode, {
+      code: "InvalidAuction",
+      number: 6030,
+    });
+
+    const { productId } = await createProduct({
+      overrides: { ...auctionConfig, payments: [auctionPayment, curvePayment] },
+    });
+    await buyProduct({ productId, buyer: admin });
+    await buyProduct({ productId, buyer: user });
+    await sleep(2000);
+
+    // the second unit is in the next tier of the curve
+    const orderId = findOrderPda(productId, user.publicKey, new anchor.BN(1));
+    const order = await studio.account.order.fetch(orderId);
+    deepStrictEqual(
+      order.payments[1].unitPrice,
+      new anchor.BN(110 * 1_000_000)
+    );
+
+    await sleep(20_000);
+    const releaseSignature = await (
+      await releaseOrderBuilder({ orderId, authority: admin })
+    ).rpc();
+    await sleep(2000);
+
+    // the curve payment is released in full
+    const releaseTx = await connection.getParsedTransaction(
+      releaseSignature,
+      "confirmed"
+    );
+    assert.equal(releaseTx.meta.err, null);
+    deepStrictEqual(
+      [
+        getTokenBalanceDelta(releaseTx, mint, user.publicKey),
+        getTokenBalanceDelta(releaseTx, mint, admin.publicKey),
+      ],
+      [0n, 110n * 1_000_000n]
+    );
+  });
+
+  it("bonding curve prices rise with supply", async () => {
+    const [solPayment] = DEFAULT_PRODUCT_CONFIG.payments;
+    const linearPayment = {
+      ...solPayment,
+      curve: {
+        linear: { tierSize: new anchor.BN(2), increment: sol(0.01) },
+      },
+    };
+
+    const invalidCurveError = await invertPromise<AnchorError>(
+      createProduct({
+        overrides: {
+          payments: [
+            {
+              ...solPayment,
+              curve: {
+                linear: { tierSize: new anchor.BN(0), increment: sol(0.01) },
+              },
+            },
+          ],
+        },
+      })
+    );
+    deepStrictEqual(invalidCurveError.error.errorCode, {
+      code: "InvalidPricingCurve",
+      number: 6032,
+    });
+
+    const { productId } = await createProduct({
+      overrides: { payments: [linearPayment] },
+    });
+
+    // two units in the first tier and one in the second
+    await buyProduct({ productId, buyer: user, quantity: 3 });
+    await sleep(2000);
+
+    const orderId = findOrderPda(productId, user.publicKey, new anchor.BN(0));
+    const order = await studio.account.order.fetch(orderId);
+    deepStrictEqual(order.payments[0].amount, sol(0.31));
+    deepStrictEqual(order.payments[0].unitPrice, sol(0.11));
+
+    // the buyer's max price guards against price moves
+    const frontRunError = await invertPromise<AnchorError>(
+      buyProduct({
+        productId,
+        buyer: user,
+        quantity: 2,
+        maxPrices: [sol(0.11)],
+      })
+    );
+    deepStrictEqual(frontRunError.error.errorCode, {
+      code: "PriceTooHigh",
+      number: 6033,
+    });
+    await buyProduct({
+      productId,
+      buyer: user,
+      maxPrices: [sol(0.11)],
+    });
+
+    // exponential curves compound every tier
+    const { productId: exponentialProductId } = await createProduct({
+      overrides: {
+        payments: [
+          {
+            ...solPayment,
+            curve: {
+              exponential: { tierSize: new anchor.BN(1), growthBps: 1000 },
+            },
+          },
+        ],
+      },
+    });
+    await buyProduct({
+      productId: exponentialProductId,
+      buyer: user,
+      quantity: 2,
+    });
+    await sleep(2000);
+
+    const exponentialOrder = await studio.account.order.fetch(
+      findOrderPda(exponentialProductId, user.publicKey, new anchor.BN(0))
+    );
+    deepStrictEqual(exponentialOrder.payments[0].amount, sol(0.21));
+  });
+
+  it("buyers are protected from price changes", async () => {
+    const id = Keypair.generate();
+    const { productId } = await createProduct({ id });
+    const product = await studio.account.merchProduct.fetch(productId);
+    const expectedTerms = getExpectedTerms(product);
+
+    await buyProduct({ productId, buyer: user, expectedTerms });
+
+    // the seller raises the price under a pending purchase
+    const [solPayment, tokenPayment] = DEFAULT_PRODUCT_CONFIG.payments;
+    await createProduct({
+      id,
+      overrides: {
+        payments: [{ ...solPayment, amount: sol(1) }, tokenPayment],
+      },
+    });
+    const priceChangedError = await invertPromise<AnchorError>(
+      buyProduct({ productId, buyer: user, expectedTerms })
+    );
+    deepStrictEqual(priceChangedError.error.errorCode, {
+      code: "TermsChanged",
+      number: 6034,
+    });
+
+    // or the commission charged by the referrer's affiliate
+    await createProduct({ id });
+    const referrer = Keypair.generate();
+    const affiliate = await registerAffiliate({
+      wallet: referrer,
+      scope: productId,
+      commissionBps: 500,
+    });
+    const buyReferred = async (expectedTerms: ExpectedTerms) =>
+      (
+        await buyProductBuilder({
+          productId,
+          buyer: user,
+          referrer: referrer.publicKey,
+          affiliate,
+          expectedTerms,
+        })
+      )
+        .preInstructions([
+          createAssociatedTokenAccountIdempotentInstruction(
+            user.publicKey,
+            getAssociatedTokenAddressSync(mint, referrer.publicKey),
+            referrer.publicKey,
+            mint
+          ),
+        ])
+        .rpc();
+
+    // the product default is not the rate charged
+    const defaultRateError = await invertPromise<AnchorError>(
+      buyReferred(getExpectedTerms(product, product.affiliateCommissionBps))
+    );
+    deepStrictEqual(defaultRateError.error.errorCode, {
+      code: "TermsChanged",
+      number: 6034,
+    });
+    await buyReferred(getExpectedTerms(product, 500));
+
+    await studio.methods
+      .updateAffiliate({ commissionBps: 5000, active: null, approved: null })
+      .accountsPartial({
+        authority: admin.publicKey,
+        affiliate,
+        product: productId,
+      })
+      .signers([admin])
+      .rpc();
+    const commissionChangedError = await invertPromise<AnchorError>(
+      buyReferred(getExpectedTerms(product, 500))
+    );
+    deepStrictEqual(commissionChangedError.error.errorCode, {
+      code: "TermsChanged",
+      number: 6034,
+    });
+
+    // or the price of the active sale phase
+    const discount = {
+      name: "discount",
+      startAt: null,
+      endAt: null,
+      gate: { public: {} },
+      priceBps: 5000,
+      maxPerWallet: 0,
+    };
+    await createProduct({ id, overrides: { phases: [discount] } });
+    const discountedTerms = getExpectedTerms(
+      await studio.account.merchProduct.fetch(productId)
+    );
+    deepStrictEqual(discountedTerms.priceBps, 5000);
+    await buyProduct({ productId, buyer: user, expectedTerms: discountedTerms });
+
+    await createProduct({
+      id,
+      overrides: { phases: [{ ...discount, priceBps: 9000 }] },
+    });
+    const phasePriceChangedError = await invertPromise<AnchorError>(
+      buyProduct({ productId, buyer: user, expectedTerms: discountedTerms })
+    );
+    deepStrictEqual(phasePriceChangedError.error.errorCode, {
+      code: "TermsChanged",
+      number: 6034,
+    });
+  });
+
+  it("sale phases apply their own rules", async () => {
+    const [solPayment] = DEFAULT_PRODUCT_CONFIG.payments;
+    const allowlist = buildAllowlist([{ wallet: user.publicKey, allocation: 0 }]);
+    const allowlistProof = {
+      allocation: 0,
+      proof: allowlist.getProof({ wallet: user.publicKey, allocation: 0 }),
+    };
+    const presale = {
+      name: "presale",
+      startAt: null,
+      endAt: null,
+      gate: { allowlist: {} },
+      priceBps: 5000,
+      maxPerWallet: 1,
+    };
+    const publicSale = {
+      name: "public",
+      startAt: null,
+      endAt: null,
+      gate: { public: {} },
+      priceBps: 10000,
+      maxPerWallet: 0,
+    };
+
+    // holder phases need a linked nft
+    const invalidPhaseError = await invertPromise<AnchorError>(
+      createProduct({
+        overrides: { phases: [{ ...publicSale, gate: { holders: {} } }] },
+      })
+    );
+    deepStrictEqual(invalidPhaseError.error.errorCode, {
+      code: "InvalidSalePhase",
+      number: 6035,
+    });
+
+    // phases cannot be free or cost more than the full price
+    for (const priceBps of [0, 10001]) {
+      const invalidPriceError = await invertPromise<AnchorError>(
+        createProduct({ overrides: { phases: [{ ...publicSale, priceBps }] } })
+      );
+      deepStrictEqual(invalidPriceError.error.errorCode, {
+        code: "InvalidSalePhase",
+        number: 6035,
+      });
+    }
+
+    const { productId: upcomingProductId } = await createProduct({
+      overrides: {
+        phases: [{ ...publicSale, startAt: unixTimestamp(60 * 60) }],
+      },
+    });
+    const noPhaseError = await invertPromise<AnchorError>(
+      buyProduct({ productId: upcomingProductId, buyer: user })
+    );
+    deepStrictEqual(noPhaseError.error.errorCode, {
+      code: "NoActivePhase",
+      number: 6036,
+    });
+
+    const { productId } = await createProduct({
+      overrides: {
+        payments: [solPayment],
+        allowlistRoot: allowlist.root,
+        phases: [{ ...presale, endAt: unixTimestamp(20) }, publicSale],
+      },
+    });
+
+    // presale is for the allowlist only, at half price
+    const notAllowlistedError = await invertPromise<AnchorError>(
+      buyProduct({ productId, buyer: admin })
+    );
+    deepStrictEqual(notAllowlistedError.error.errorCode, {
+      code: "NotAllowlisted",
+      number: 6014,
+    });
+
+    await buyProduct({ productId, buyer: user, allowlistProof });
+    await sleep(2000);
+
+    const presaleOrder = await studio.account.order.fetch(
+      findOrderPda(productId, user.publicKey, new anchor.BN(0))
+    );
+    deepStrictEqual(presaleOrder.payments[0].unitPrice, sol(0.05));
+
+    const phaseLimitError = await invertPromise<AnchorError>(
+      buyProduct({ productId, buyer: user, allowlistProof })
+    );
+    deepStrictEqual(phaseLimitError.error.errorCode, {
+      code: "NoMoreWalletPurchases",
+      number: 6028,
+    });
+
+    // then anyone at full price
+    await sleep(20_000);
+    await buyProduct({ productId, buyer: admin });
+    await sleep(2000);
+
+    const publicOrder = await studio.account.order.fetch(
+      findOrderPda(productId, admin.publicKey, new anchor.BN(1))
+    );
+    deepStrictEqual(publicOrder.payments[0].unitPrice, sol(0.1));
+  });
+
+  it("buy product variants", async () => {
+    const small = {
+      label: "S",
+      maxSupply: new anchor.BN(1),
+      amounts: null,
+      currentSupply: new anchor.BN(0),
+    };
+    const extraLarge = {
+      label: "XL",
+      maxSupply: null,
+      amounts: [sol(0.2), new anchor.BN(200 * 1_000_000)],
+      currentSupply: new anchor.BN(0),
+    };
+    const id = Keypair.generate();
+    const { productId } = await createProduct({
+      id,
+      overrides: {
+        maxSupply: { some: [new anchor.BN(3)] },
+        variants: [small, extraLarge],
+      },
+    });
+
+    // auctioned variants cannot start below the floor
+    const [solPayment] = DEFAULT_PRODUCT_CONFIG.payments;
+    const belowFloorError = await invertPromise<AnchorError>(
+      createProduct({
+        overrides: {
+          saleStartAt: unixTimestamp(-60),
+          payments: [
+            {
+              ...solPayment,
+              auction: {
+                floorAmount: sol(0.05),
+                interval: new anchor.BN(60 * 60),
+                step: sol(0.01),
+              },
+            },
+          ],
+          variants: [{ ...small, amounts: [sol(0.01)] }],
+        },
+      })
+    );
+    deepStrictEqual(belowFloorError.error.errorCode, {
+      code: "InvalidVariant",
+      number: 6037,
+    });
+
+    // a variant must be selected
+    for (const variant of [undefined, 5]) {
+      const variantError = await invertPromise<AnchorError>(
+        buyProduct({ productId, buyer: user, variant })
+      );
+      deepStrictEqual(variantError.error.errorCode, {
+        code: "InvalidVariant",
+        number: 6037,
+      });
+    }
+
+    await buyProduct({ productId, buyer: user, variant: 0 });
+    await sleep(2000);
+
+    // variants have their own supply
+    const variantSupplyError = await invertPromise<AnchorError>(
+      buyProduct({ productId, buyer: user, variant: 0 })
+    );
+    deepStrictEqual(variantSupplyError.error.errorCode, {
+      code: "NoMoreSupply",
+      number: 6004,
+    });
+
+    // and their own price
+    await buyProduct({ productId, buyer: user, variant: 1, quantity: 2 });
+    await sleep(2000);
+
+    const order = await studio.account.order.fetch(
+      findOrderPda(productId, user.publicKey, new anchor.BN(1))
+    );
+    assert.equal(order.variant, 1);
+    deepStrictEqual(order.payments[0].unitPrice, sol(0.2));
+
+    const product = await studio.account.merchProduct.fetch(productId);
+    deepStrictEqual(
+      product.variants.map(({ currentSupply }) => currentSupply),
+      [new anchor.BN(1), new anchor.BN(2)]
+    );
+
+    // the product's total supply is still enforced
+    const totalSupplyError = await invertPromise<AnchorError>(
+      buyProduct({ productId, buyer: user, variant: 1 })
+    );
+    deepStrictEqual(totalSupplyError.error.errorCode, {
+      code: "NoMoreSupply",
+      number: 6004,
+    });
+
+    // orders refer to variants by index: sold ones cannot be dropped or moved
+    for (const variants of [[extraLarge, small], [small]]) {
+      const movedVariantError = await invertPromise<AnchorError>(
+        createProduct({
+          id,
+          overrides: { maxSupply: { some: [new anchor.BN(5)] }, variants },
+        })
+      );
+      deepStrictEqual(movedVariantError.error.errorCode, {
+        code: "InvalidVariant",
+        number: 6037,
+      });
+    }
+
+    // but new variants can be added after them
+    const medium = { ...small, label: "M", maxSupply: null };
+    await createProduct({
+      id,
+      overrides: {
+        maxSupply: { some: [new anchor.BN(5)] },
+        variants: [small, extraLarge, medium],
+      },
+    });
+    const updatedProduct = await studio.account.merchProduct.fetch(productId);
+    deepStrictEqual(
+      updatedProduct.variants.map(({ currentSupply }) => currentSupply),
+      [new anchor.BN(1), new anchor.BN(2), new anchor.BN(0)]
+    );
+
+    // the amounts of every variant are part of the signed terms
+    const expectedTerms = getExpectedTerms(updatedProduct);
+    await createProduct({
+      id,
+      overrides: {
+        maxSupply: { some: [new anchor.BN(5)] },
+        variants: [
+          small,
+          {
+            ...extraLarge,
+            amounts: [sol(0.3), new anchor.BN(300 * 1_000_000)],
+          },
+          medium,
+        ],
+      },
+    });
+    const amountsChangedError = await invertPromise<AnchorError>(
+      buyProduct({ productId, buyer: user, variant: 2, expectedTerms })
+    );
+    deepStrictEqual(amountsChangedError.error.errorCode, {
+      code: "TermsChanged",
+      number: 6034,
+    });
+  });
 });
@@ -1,25 +1,310 @@
 import * as anchor from "@coral-xyz/anchor";
 import { IdlAccounts, IdlTypes, Program } from "@coral-xyz/anchor";
+import { createHash } from "crypto";
 
-import { AccountMeta, Keypair, PublicKey } from "./web3";
+import {
+  AccountMeta,
+  Connection,
+  Keypair,
+  PublicKey,
+  TransactionInstruction,
+} from "./web3";
 import { FosterStudio } from "../target/types/foster_studio";
 import { admin, DEFAULT_PRODUCT_CONFIG } from "./constants";
-import { findMasterEditionPda } from "./mplTokenMetadata";
-import { getAssociatedTokenAddressSync } from "@solana/spl-token";
+import { findMasterEditionPda, findMetadataPda } from "./mplTokenMetadata";
+import {
+  createAssociatedTokenAccountIdempotentInstruction,
+  getAssociatedTokenAddressSync,
+  TOKEN_2022_PROGRAM_ID,
+} from "@solana/spl-token";
 
 export const studio = anchor.workspace.FosterStudio as Program<FosterStudio>;
 
+// token program owning each payment mint
+const tokenPrograms = new Map<string, PublicKey>();
+export async function getTokenProgram(mint: PublicKey): Promise<PublicKey> {
+  const key = mint.toBase58();
+  if (!tokenPrograms.has(key)) {
+    const mintInfo = await studio.provider.connection.getAccountInfo(mint);
+    tokenPrograms.set(key, mintInfo.owner);
+  }
+  return tokenPrograms.get(key);
+}
+
+export interface PaymentMint {
+  mint: PublicKey;
+  tokenProgram: PublicKey;
+}
+async function getPaymentMints(
+  payments: { mint: PublicKey }[]
+): Promise<PaymentMint[]> {
+  return Promise.all(
+    payments.map(async ({ mint }) => ({
+      mint,
+      tokenProgram: mint.equals(PublicKey.default)
+        ? null
+        : await getTokenProgram(mint),
+    }))
+  );
+}
+function getToken2022Program(mints: PaymentMint[]): PublicKey | null {
+  return mints.some(({ tokenProgram }) => tokenProgram?.equals(TOKEN_2022_PROGRAM_ID))
+    ? TOKEN_2022_PROGRAM_ID
+    : null;
+}
+// mints are writable when releasing or refunding escrowed payments, to harvest
+// the transfer fees withheld in the order's vaults
+function mintMeta(mint: PublicKey, isWritable = false): AccountMeta {
+  return { pubkey: mint, isSigner: false, isWritable };
+}
+
 export type MerchProduct = IdlAccounts<FosterStudio>["merchProduct"];
+export type Order = IdlAccounts<FosterStudio>["order"];
+export type AllowlistProof = IdlTypes<FosterStudio>["allowlistProof"];
 export type MerchProductConfig = IdlTypes<FosterStudio>["merchProductConfig"];
+export type Config = IdlAccounts<FosterStudio>["config"];
+export type SalesStats = IdlAccounts<FosterStudio>["salesStats"];
+
+// discriminator of the self-cpi instruction used by `emit_cpi!`
+export const EVENT_IX_TAG = Buffer.from([
+  0xe4, 0x45, 0xa5, 0x2e, 0x51, 0xcb, 0x9a, 0x1d,
+]);
+export async function getCpiEvents(
+  connection: Connection,
+  signature: string
+): Promise<{ name: string; data: any }[]> {
+  const tx = await connection.getTransaction(signature, {
+    commitment: "confirmed",
+    maxSupportedTransactionVersion: 0,
+  });
+  const accountKeys = tx.transaction.message.getAccountKeys();
+
+  return (tx.meta.innerInstructions ?? []).flatMap(({ instructions }) =>
+    instructions.flatMap(({ programIdIndex, data }) => {
+      if (!accountKeys.get(programIdIndex).equals(studio.programId)) return [];
+
+      const ixData = anchor.utils.bytes.bs58.decode(data);
+      if (!ixData.subarray(0, 8).equals(EVENT_IX_TAG)) return [];
+
+      const event = studio.coder.events.decode(
+        anchor.utils.bytes.base64.encode(ixData.subarray(8))
+      );
+      return event ? [event] : [];
+    })
+  );
+}
+
+// scope the claims of a product are counted in, claim pools are namespaced by
+// the wallet that set them
+export function getClaimScope(
+  productId: PublicKey,
+  product: MerchProduct
+): PublicKey {
+  return product.claimPoolScope ?? productId;
+}
 
 export const CLAIM_MARKER = "claim";
-export function findClaimMarkerPda(mint: PublicKey): PublicKey {
+export function findClaimMarkerPda(
+  scope: PublicKey,
+  mint: PublicKey
+): PublicKey {
+  return PublicKey.findProgramAddressSync(
+    [
+      Buffer.from(CLAIM_MARKER),
+      scope.toBuffer(),
+      findMasterEditionPda(mint).toBuffer(),
+    ],
+    studio.programId
+  )[0];
+}
+
+export function findNftClaimMarkerPda(
+  scope: PublicKey,
+  nftMint: PublicKey
+): PublicKey {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from(CLAIM_MARKER), scope.toBuffer(), nftMint.toBuffer()],
+    studio.programId
+  )[0];
+}
+
+export function findLegacyClaimMarkerPda(claimSeed: PublicKey): PublicKey {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from(CLAIM_MARKER), claimSeed.toBuffer()],
+    studio.programId
+  )[0];
+}
+
+export const ALLOWLIST_MARKER = "allowlist";
+export function findAllowlistMarkerPda(
+  productId: PublicKey,
+  buyer: PublicKey
+): PublicKey {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from(ALLOWLIST_MARKER), productId.toBuffer(), buyer.toBuffer()],
+    studio.programId
+  )[0];
+}
+
+export const PURCHASE_MARKER = "purchase";
+export function findPurchaseMarkerPda(
+  productId: PublicKey,
+  buyer: PublicKey
+): PublicKey {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from(PURCHASE_MARKER), productId.toBuffer(), buyer.toBuffer()],
+    studio.programId
+  )[0];
+}
+
+export function findPhasePurchaseMarkerPda(
+  productId: PublicKey,
+  buyer: PublicKey,
+  phaseIndex: number
+): PublicKey {
+  return PublicKey.findProgramAddressSync(
+    [
+      Buffer.from(PURCHASE_MARKER),
+      productId.toBuffer(),
+      buyer.toBuffer(),
+      Buffer.from([phaseIndex]),
+    ],
+    studio.programId
+  )[0];
+}
+
+// index of the first sale phase whose window contains the current time
+export function findActivePhaseIndex(product: MerchProduct): number {
+  const now = Date.now() / 1000;
+  return product.phases.findIndex(
+    ({ startAt, endAt }) =>
+      (!startAt || startAt.toNumber() <= now) &&
+      (!endAt || now <= endAt.toNumber())
+  );
+}
+
+export const ORDER = "order";
+export function findOrderPda(
+  productId: PublicKey,
+  buyer: PublicKey,
+  orderNumber: anchor.BN
+): PublicKey {
   return PublicKey.findProgramAddressSync(
-    [Buffer.from(CLAIM_MARKER), findMasterEditionPda(mint).toBuffer()],
+    [
+      Buffer.from(ORDER),
+      productId.toBuffer(),
+      buyer.toBuffer(),
+      orderNumber.toArrayLike(Buffer, "le", 8),
+    ],
     studio.programId
   )[0];
 }
 
+export const CONFIG = "config";
+export function findConfigPda(): PublicKey {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from(CONFIG)],
+    studio.programId
+  )[0];
+}
+
+export const AFFILIATE = "affiliate";
+export function findAffiliatePda(
+  scope: PublicKey,
+  wallet: PublicKey
+): PublicKey {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from(AFFILIATE), scope.toBuffer(), wallet.toBuffer()],
+    studio.programId
+  )[0];
+}
+
+export interface RegisterAffiliateArgs {
+  wallet: Keypair;
+  // product or seller the affiliate is registered with
+  scope: PublicKey;
+  // seller approving the affiliate, if any
+  seller?: Keypair;
+  commissionBps?: number;
+}
+export async function registerAffiliate({
+  wallet,
+  scope,
+  seller = admin,
+  commissionBps = null,
+}: RegisterAffiliateArgs): Promise<PublicKey> {
+  const affiliate = findAffiliatePda(scope, wallet.publicKey);
+  await studio.methods
+    .registerAffiliate(scope)
+    .accountsPartial({
+      payer: admin.publicKey,
+      wallet: wallet.publicKey,
+      affiliate,
+    })
+    .signers([admin, wallet])
+    .rpc();
+
+  if (seller)
+    await studio.methods
+      .updateAffiliate({
+        commissionBps,
+        active: null,
+        approved: true,
+      })
+      .accountsPartial({
+        authority: seller.publicKey,
+        affiliate,
+        product: scope.equals(seller.publicKey) ? null : scope,
+      })
+      .signers([seller])
+      .rpc();
+
+  return affiliate;
+}
+
+export const PRODUCT_STATS = "product_stats";
+export function findProductStatsPda(productId: PublicKey): PublicKey {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from(PRODUCT_STATS), productId.toBuffer()],
+    studio.programId
+  )[0];
+}
+
+export const REFERRER_STATS = "referrer_stats";
+export function findReferrerStatsPda(referrer: PublicKey): PublicKey {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from(REFERRER_STATS), referrer.toBuffer()],
+    studio.programId
+  )[0];
+}
+
+export const BPF_LOADER_UPGRADEABLE_PROGRAM_ID = new PublicKey(
+  "BPFLoaderUpgradeab1e11111111111111111111111"
+);
+export function findProgramDataPda(): PublicKey {
+  return PublicKey.findProgramAddressSync(
+    [studio.programId.toBuffer()],
+    BPF_LOADER_UPGRADEABLE_PROGRAM_ID
+  )[0];
+}
+
+// platform fee charged by the config on `amount` of `mint`
+export function getPlatformFee(
+  config: Config,
+  mint: PublicKey,
+  amount: anchor.BN
+): { feeRecipient: PublicKey; platformFee: anchor.BN } | null {
+  const feeRecipient = config.feeRecipients.find((feeRecipient) =>
+    feeRecipient.mint.equals(mint)
+  );
+  if (!feeRecipient) return null;
+
+  const platformFee = amount.muln(config.platformFeeBps).divn(10_000);
+  if (platformFee.isZero()) return null;
+
+  return { feeRecipient: feeRecipient.recipient, platformFee };
+}
+
 export interface CreateProductArgs {
   id?: Keypair;
   overrides?: Partial<MerchProductConfig>;
@@ -60,25 +345,276 @@ export async function buyProduct(args: BuyProductBuilderArgs): Promise<string> {
   return builder.rpc();
 }
 
+export type ExpectedTerms = IdlTypes<FosterStudio>["expectedTerms"];
+// terms of the product as currently configured, for the buyer to commit to.
+// `affiliateCommissionBps` is the rate of the referrer's affiliate, 0 without referrer
+export function getExpectedTerms(
+  product: MerchProduct,
+  affiliateCommissionBps = 0
+): ExpectedTerms {
+  // borsh vec: u32 length followed by the items
+  const vec = (items: Buffer[]) => {
+    const length = Buffer.alloc(4);
+    length.writeUInt32LE(items.length);
+    return Buffer.concat([length, ...items]);
+  };
+  const payments = vec(
+    product.payments.map((payment) =>
+      studio.coder.types.encode("paymentConfig", payment)
+    )
+  );
+  // borsh option: 0, or 1 followed by the value
+  const variantAmounts = vec(
+    product.variants.map(({ amounts }) =>
+      amounts
+        ? Buffer.concat([
+            Buffer.from([1]),
+            vec(amounts.map((amount) => amount.toArrayLike(Buffer, "le", 8))),
+          ])
+        : Buffer.from([0])
+    )
+  );
+
+  const phaseIndex = findActivePhaseIndex(product);
+
+  return {
+    paymentsHash: [
+      ...createHash("sha256")
+        .update(payments)
+        .update(variantAmounts)
+        .digest(),
+    ],
+    affiliateCommissionBps,
+    priceBps: phaseIndex < 0 ? 10000 : product.phases[phaseIndex].priceBps,
+  };
+}
+
 export interface BuyProductBuilderArgs {
   productId: PublicKey;
   buyer: Keypair;
   product?: MerchProduct;
+  // sequence number of the order, defaults to the product's order count
+  orderNumber?: anchor.BN;
+  // edition mint
   edition?: PublicKey;
+  // collection nft mint
+  nft?: PublicKey;
+  // defaults to the buyer's ata of the edition or nft
+  nftTokenAccount?: PublicKey;
   referrer?: PublicKey;
+  // defaults to the referrer's registration with the seller, null to omit
+  affiliate?: PublicKey | null;
+  quantity?: number;
+  // index of the variant to buy
+  variant?: number;
+  paymentOption?: number;
+  allowlistProof?: AllowlistProof;
+  // highest accepted unit price of each payment of the option
+  maxPrices?: anchor.BN[];
+  expectedTerms?: ExpectedTerms;
 }
 export async function buyProductBuilder({
   productId,
   buyer,
   product,
+  orderNumber,
   edition,
+  nft,
+  nftTokenAccount,
   referrer = null,
+  affiliate,
+  quantity = 1,
+  variant = null,
+  paymentOption = 0,
+  allowlistProof = null,
+  maxPrices = null,
+  expectedTerms = null,
 }: BuyProductBuilderArgs) {
   product ??= await studio.account.merchProduct.fetch(productId);
+  const config = await studio.account.config.fetch(findConfigPda());
   const linkedMasterNft = product.linkedMasterNft;
+  orderNumber ??= product.orderCount;
+  const orderId = findOrderPda(productId, buyer.publicKey, orderNumber);
+  const phaseIndex = findActivePhaseIndex(product);
+
+  const payments = product.payments.filter(
+    ({ option }) => option == paymentOption
+  );
+  const paymentMints = await getPaymentMints(payments);
+  const preInstructions: TransactionInstruction[] = [];
+  const remainingAccounts: AccountMeta[] = payments.flatMap(
+    ({ mint, recipient, splits, amount }, i) => {
+      const { tokenProgram } = paymentMints[i];
+      // split payments are paid to every split recipient
+      const recipients: AccountMeta[] = (
+        splits.length ? splits.map(({ recipient }) => recipient) : [recipient]
+      ).map((pubkey) => ({ pubkey, isSigner: false, isWritable: true }));
+      const platformFee = getPlatformFee(config, mint, amount.muln(quantity));
+      const feeAccounts: AccountMeta[] = platformFee
+        ? [
+            {
+              pubkey: platformFee.feeRecipient,
+              isSigner: false,
+              isWritable: true,
+            },
+          ]
+        : [];
+
+      // escrowed payment
+      if (product.escrow) {
+        // sol is held by the order itself
+        if (mint.equals(PublicKey.default)) return [];
+
+        const vault = getAssociatedTokenAddressSync(
+          mint,
+          orderId,
+          true,
+          tokenProgram
+        );
+        preInstructions.push(
+          createAssociatedTokenAccountIdempotentInstruction(
+            buyer.publicKey,
+            vault,
+            orderId,
+            mint,
+            tokenProgram
+          )
+        );
+        return [
+          mintMeta(mint),
+          {
+            pubkey: getAssociatedTokenAddressSync(
+              mint,
+              buyer.publicKey,
+              true,
+              tokenProgram
+            ),
+            isSigner: false,
+            isWritable: true,
+          },
+          {
+            pubkey: vault,
+            isSigner: false,
+            isWritable: true,
+          },
+        ];
+      }
+      // sol payment
+      else if (mint.equals(PublicKey.default))
+        return [...recipients, ...feeAccounts];
+      // token payment
+      else {
+        const keys = [
+          mintMeta(mint),
+          {
+            pubkey: getAssociatedTokenAddressSync(
+              mint,
+              buyer.publicKey,
+              true,
+              tokenProgram
+            ),
+            isSigner: false,
+            isWritable: true,
+          },
+          ...recipients,
+        ];
+
+        if (referrer)
+          keys.push({
+            pubkey: getAssociatedTokenAddressSync(
+              mint,
+              referrer,
+              true,
+              tokenProgram
+            ),
+            isSigner: false,
+            isWritable: true,
+          });
+
+        return [...keys, ...feeAccounts];
+      }
+    }
+  );
+
+  return studio.methods
+    .buyProduct({
+      orderNumber,
+      quantity,
+      variant,
+      paymentOption,
+      allowlistProof,
+      maxPrices,
+      expectedTerms,
+    })
+    .accountsPartial({
+      buyer: buyer.publicKey,
+      product: productId,
+      order: orderId,
+      masterEditionPda: linkedMasterNft
+        ? findMasterEditionPda(linkedMasterNft)
+        : null,
+      nftMint: edition ?? nft ?? null,
+      nftTokenAccount:
+        nftTokenAccount ??
+        (edition || nft
+          ? getAssociatedTokenAddressSync(edition ?? nft, buyer.publicKey, true)
+          : null),
+      editionPda: edition ? findMasterEditionPda(edition) : null,
+      nftMetadata: nft ? findMetadataPda(nft) : null,
+      claimMarker: edition
+        ? findClaimMarkerPda(getClaimScope(productId, product), edition)
+        : nft
+        ? findNftClaimMarkerPda(getClaimScope(productId, product), nft)
+        : null,
+      legacyClaimMarker: edition
+        ? findLegacyClaimMarkerPda(findMasterEditionPda(edition))
+        : nft
+        ? findLegacyClaimMarkerPda(nft)
+        : null,
+      allowlistMarker: allowlistProof?.allocation
+        ? findAllowlistMarkerPda(productId, buyer.publicKey)
+        : null,
+      purchaseMarker: product.maxPerWallet
+        ? findPurchaseMarkerPda(productId, buyer.publicKey)
+        : null,
+      phasePurchaseMarker: product.phases[phaseIndex]?.maxPerWallet
+        ? findPhasePurchaseMarkerPda(productId, buyer.publicKey, phaseIndex)
+        : null,
+      referrer,
+      affiliate:
+        referrer && affiliate !== null
+          ? affiliate ?? findAffiliatePda(product.authority, referrer)
+          : null,
+      productStats: findProductStatsPda(productId),
+      referrerStats: referrer ? findReferrerStatsPda(referrer) : null,
+      token2022Program: getToken2022Program(paymentMints),
+    })
+    .preInstructions(preInstructions)
+    .remainingAccounts(remainingAccounts)
+    .signers([buyer]);
+}
+
+export interface ReleaseOrderBuilderArgs {
+  orderId: PublicKey;
+  authority: Keypair;
+  order?: Order;
+}
+export async function releaseOrderBuilder({
+  orderId,
+  authority,
+  order,
+}: ReleaseOrderBuilderArgs) {
+  order ??= await studio.account.order.fetch(orderId);
+  const product = await studio.account.merchProduct.fetch(order.product);
+
+  const paymentMints = await getPaymentMints(order.payments);
+  const remainingAccounts: AccountMeta[] = order.payments.flatMap(
+    ({ mint, recipient, platformFee, feeRecipient }, i) => {
+      const { tokenProgram } = paymentMints[i];
+      const feeAccounts: AccountMeta[] = platformFee.isZero()
+        ? []
+        : [{ pubkey: feeRecipient, isSigner: false, isWritable: true }];
 
-  const remainingAccounts: AccountMeta[] = product.payments.flatMap(
-    ({ mint, recipient }) => {
       // sol payment
       if (mint.equals(PublicKey.default))
         return [
@@ -87,12 +623,19 @@ export async function buyProductBuilder({
             isSigner: false,
             isWritable: true,
           },
+          ...feeAccounts,
         ];
       // token payment
       else {
         const keys = [
+          mintMeta(mint, true),
           {
-            pubkey: getAssociatedTokenAddressSync(mint, buyer.publicKey, true),
+            pubkey: getAssociatedTokenAddressSync(
+              mint,
+              orderId,
+              true,
+              tokenProgram
+            ),
             isSigner: false,
             isWritable: true,
           },
@@ -103,29 +646,193 @@ export async function buyProductBuilder({
           },
         ];
 
-        if (referrer)
+        if (order.referrer)
           keys.push({
-            pubkey: getAssociatedTokenAddressSync(mint, referrer, true),
+            pubkey: getAssociatedTokenAddressSync(
+              mint,
+              order.referrer,
+              true,
+              tokenProgram
+            ),
             isSigner: false,
             isWritable: true,
           });
 
-        return keys;
+        // auction rebates are paid back to the buyer, after the platform fee
+        const rebateAccounts: AccountMeta[] = product.auctionRebate
+          ? [
+              {
+                pubkey: getAssociatedTokenAddressSync(
+                  mint,
+                  order.buyer,
+                  true,
+                  tokenProgram
+                ),
+                isSigner: false,
+                isWritable: true,
+              },
+            ]
+          : [];
+
+        return [...keys, ...feeAccounts, ...rebateAccounts];
       }
     }
   );
 
   return studio.methods
-    .buyProduct()
+    .releaseOrder()
     .accountsPartial({
-      buyer: buyer.publicKey,
-      product: productId,
-      masterEditionPda: linkedMasterNft
-        ? findMasterEditionPda(linkedMasterNft)
+      authority: authority.publicKey,
+      product: order.product,
+      order: orderId,
+      buyer: order.buyer,
+      referrer: order.referrer,
+      productStats: product.auctionRebate
+        ? findProductStatsPda(order.product)
         : null,
-      editionPda: edition ? findMasterEditionPda(edition) : null,
-      claimMarker: edition ? findClaimMarkerPda(edition) : null,
-      referrer,
+      token2022Program: getToken2022Program(paymentMints),
+    })
+    .remainingAccounts(remainingAccounts)
+    .signers([authority]);
+}
+
+export async function getRefundAccounts(
+  orderId: PublicKey,
+  order: Order,
+  authority?: PublicKey
+): Promise<{ remainingAccounts: AccountMeta[]; token2022Program: PublicKey }> {
+  const paymentMints = await getPaymentMints(order.payments);
+  const remainingAccounts = order.payments.flatMap(({ mint }, i) => {
+    const { tokenProgram } = paymentMints[i];
+    // sol is refunded from the order or the authority directly
+    if (mint.equals(PublicKey.default)) return [];
+
+    return [
+      mintMeta(mint, true),
+      {
+        // escrowed tokens are refunded from the order's vault
+        pubkey: getAssociatedTokenAddressSync(
+          mint,
+          order.status.escrowed ? orderId : authority,
+          true,
+          tokenProgram
+        ),
+        isSigner: false,
+        isWritable: true,
+      },
+      {
+        pubkey: getAssociatedTokenAddressSync(
+          mint,
+          order.buyer,
+          true,
+          tokenProgram
+        ),
+        isSigner: false,
+        isWritable: true,
+      },
+    ];
+  });
+
+  return {
+    remainingAccounts,
+    token2022Program: getToken2022Program(paymentMints),
+  };
+}
+
+export function findOrderClaimMarkerPda(order: Order): PublicKey | null {
+  if (!order.edition) return null;
+
+  return PublicKey.findProgramAddressSync(
+    [
+      Buffer.from(CLAIM_MARKER),
+      order.claimScope.toBuffer(),
+      order.edition.toBuffer(),
+    ],
+    studio.programId
+  )[0];
+}
+
+// purchase markers the order was counted in, given back on refund
+export function findOrderPurchaseMarkerPdas(order: Order): {
+  allowlistMarker: PublicKey | null;
+  purchaseMarker: PublicKey | null;
+  phasePurchaseMarker: PublicKey | null;
+} {
+  return {
+    allowlistMarker: order.allowlistCounted
+      ? findAllowlistMarkerPda(order.product, order.buyer)
+      : null,
+    purchaseMarker: order.walletCounted
+      ? findPurchaseMarkerPda(order.product, order.buyer)
+      : null,
+    phasePurchaseMarker:
+      order.phaseCounted === null
+        ? null
+        : findPhasePurchaseMarkerPda(
+            order.product,
+            order.buyer,
+            order.phaseCounted
+          ),
+  };
+}
+
+export interface RefundOrderBuilderArgs {
+  orderId: PublicKey;
+  authority: Keypair;
+  order?: Order;
+}
+export async function refundOrderBuilder({
+  orderId,
+  authority,
+  order,
+}: RefundOrderBuilderArgs) {
+  order ??= await studio.account.order.fetch(orderId);
+  const { remainingAccounts, token2022Program } = await getRefundAccounts(
+    orderId,
+    order,
+    authority.publicKey
+  );
+
+  return studio.methods
+    .refundOrder()
+    .accountsPartial({
+      authority: authority.publicKey,
+      product: order.product,
+      order: orderId,
+      buyer: order.buyer,
+      claimMarker: findOrderClaimMarkerPda(order),
+      ...findOrderPurchaseMarkerPdas(order),
+      token2022Program,
+    })
+    .remainingAccounts(remainingAccounts)
+    .signers([authority]);
+}
+
+export interface ClaimOrderRefundBuilderArgs {
+  orderId: PublicKey;
+  buyer: Keypair;
+  order?: Order;
+}
+export async function claimOrderRefundBuilder({
+  orderId,
+  buyer,
+  order,
+}: ClaimOrderRefundBuilderArgs) {
+  order ??= await studio.account.order.fetch(orderId);
+  const { remainingAccounts, token2022Program } = await getRefundAccounts(
+    orderId,
+    order
+  );
+
+  return studio.methods
+    .claimOrderRefund()
+    .accountsPartial({
+      buyer: buyer.publicKey,
+      product: order.product,
+      order: orderId,
+      claimMarker: findOrderClaimMarkerPda(order),
+      ...findOrderPurchaseMarkerPdas(order),
+      token2022Program,
     })
     .remainingAccounts(remainingAccounts)
     .signers([buyer]);
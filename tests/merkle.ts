@@ -0,0 +1,66 @@
+import { createHash } from "crypto";
+
+import { PublicKey } from "./web3";
+
+export interface AllowlistEntry {
+  wallet: PublicKey;
+  // 0 for unlimited
+  allocation: number;
+}
+
+export interface Allowlist {
+  root: number[];
+  getProof(entry: AllowlistEntry): number[][];
+}
+
+function hash(...parts: Buffer[]): Buffer {
+  const hasher = createHash("sha256");
+  for (const part of parts) hasher.update(part);
+  return hasher.digest();
+}
+
+export function allowlistLeaf({ wallet, allocation }: AllowlistEntry): Buffer {
+  const allocationBuffer = Buffer.alloc(4);
+  allocationBuffer.writeUInt32LE(allocation);
+  return hash(
+    Buffer.from([0x00]),
+    Buffer.concat([wallet.toBuffer(), allocationBuffer])
+  );
+}
+
+function hashPair(a: Buffer, b: Buffer): Buffer {
+  return Buffer.compare(a, b) <= 0
+    ? hash(Buffer.from([0x01]), a, b)
+    : hash(Buffer.from([0x01]), b, a);
+}
+
+// merkle tree with sorted pairs, matching `verify_merkle_proof`
+export function buildAllowlist(entries: AllowlistEntry[]): Allowlist {
+  const layers: Buffer[][] = [entries.map(allowlistLeaf)];
+  while (layers[layers.length - 1].length > 1) {
+    const layer = layers[layers.length - 1];
+    const nextLayer: Buffer[] = [];
+    for (let i = 0; i < layer.length; i += 2)
+      nextLayer.push(
+        i + 1 < layer.length ? hashPair(layer[i], layer[i + 1]) : layer[i]
+      );
+    layers.push(nextLayer);
+  }
+
+  return {
+    root: [...layers[layers.length - 1][0]],
+    getProof(entry) {
+      const leaf = allowlistLeaf(entry);
+      let index = layers[0].findIndex((node) => node.equals(leaf));
+      if (index == -1) throw new Error("entry not in allowlist");
+
+      const proof: number[][] = [];
+      for (const layer of layers.slice(0, -1)) {
+        const siblingIndex = index % 2 == 0 ? index + 1 : index - 1;
+        if (siblingIndex < layer.length) proof.push([...layer[siblingIndex]]);
+        index = Math.floor(index / 2);
+      }
+      return proof;
+    },
+  };
+}
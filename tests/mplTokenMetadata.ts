@@ -3,6 +3,8 @@ import {
   signerIdentity,
   percentAmount,
   createSignerFromKeypair,
+  none,
+  some,
 } from "@metaplex-foundation/umi";
 import { createUmi } from "@metaplex-foundation/umi-bundle-defaults";
 import {
@@ -11,8 +13,10 @@ import {
   TokenStandard,
   MPL_TOKEN_METADATA_PROGRAM_ID,
   findMasterEditionPda as findMasterEditionPdaUmi,
+  findMetadataPda as findMetadataPdaUmi,
   printSupply,
   printV2,
+  verifyCollectionV1,
 } from "@metaplex-foundation/mpl-token-metadata";
 import {
   fromWeb3JsPublicKey,
@@ -33,6 +37,15 @@ export function findMasterEditionPda(mint: PublicKey): PublicKey {
   );
 }
 
+export function findMetadataPda(mint: PublicKey): PublicKey {
+  const umi = createUmi("http://localhost");
+  return toWeb3JsPublicKey(
+    findMetadataPdaUmi(umi, {
+      mint: fromWeb3JsPublicKey(mint),
+    })[0]
+  );
+}
+
 export interface CreateAndMintNftArgs {
   connection: Connection;
   payer: Keypair;
@@ -44,6 +57,8 @@ export interface CreateAndMintNftArgs {
   uri: string;
 
   maxSupply: number;
+  collection?: PublicKey;
+  isCollection?: boolean;
 }
 export async function createAndMintNft({
   connection,
@@ -54,6 +69,8 @@ export async function createAndMintNft({
   symbol,
   uri,
   maxSupply,
+  collection,
+  isCollection = false,
 }: CreateAndMintNftArgs) {
   const umi = createUmi(connection);
   const signer = createSignerFromKeypair(umi, fromWeb3JsKeypair(payer));
@@ -75,6 +92,10 @@ export async function createAndMintNft({
         : maxSupply === null
         ? printSupply("Unlimited")
         : printSupply("Limited", [maxSupply]),
+    collection: collection
+      ? some({ key: fromWeb3JsPublicKey(collection), verified: false })
+      : none(),
+    isCollection,
   }).sendAndConfirm(umi);
 
   return {
@@ -116,3 +137,29 @@ export async function createPrint({
     signature,
   };
 }
+
+export interface VerifyCollectionArgs {
+  connection: Connection;
+  collectionAuthority: Keypair;
+  mint: PublicKey;
+  collectionMint: PublicKey;
+}
+export async function verifyCollection({
+  connection,
+  collectionAuthority,
+  mint,
+  collectionMint,
+}: VerifyCollectionArgs) {
+  const umi = createUmi(connection);
+  const signer = createSignerFromKeypair(
+    umi,
+    fromWeb3JsKeypair(collectionAuthority)
+  );
+  umi.use(signerIdentity(signer)).use(mplTokenMetadata());
+
+  return verifyCollectionV1(umi, {
+    metadata: findMetadataPdaUmi(umi, { mint: fromWeb3JsPublicKey(mint) }),
+    collectionMint: fromWeb3JsPublicKey(collectionMint),
+    authority: signer,
+  }).sendAndConfirm(umi);
+}
@@ -8,13 +8,26 @@ import {
 import {
   createAssociatedTokenAccountIdempotentInstruction,
   createInitializeMint2Instruction,
+  createInitializeTransferFeeConfigInstruction,
   createMintToInstruction,
+  ExtensionType,
   getAssociatedTokenAddressSync,
+  getMintLen,
   MINT_SIZE,
+  TOKEN_2022_PROGRAM_ID,
   TOKEN_PROGRAM_ID,
 } from "@solana/spl-token";
-import { mint, admin, user, mintKeypair } from "./constants";
+import {
+  mint,
+  admin,
+  user,
+  mintKeypair,
+  feeMint,
+  feeMintKeypair,
+  FEE_MINT_TRANSFER_FEE_BPS,
+} from "./constants";
 import { log } from "./logging";
+import { findProgramDataPda, studio } from "./lib";
 import { sleep } from "./utils";
 
 export default async function setup(connection: Connection) {
@@ -61,4 +74,87 @@ export default async function setup(connection: Connection) {
     [user, mintKeypair]
   );
   log(`mint accounts setup: ${setupSignature}`);
+
+  // initialize config, without platform fees
+  const configSignature = await studio.methods
+    .initializeConfig({
+      admin: admin.publicKey,
+      platformFeeBps: 0,
+      feeRecipients: [],
+      paused: false,
+    })
+    .accountsPartial({
+      authority: studio.provider.publicKey,
+      programData: findProgramDataPda(),
+    })
+    .rpc();
+  log(`config initialized: ${configSignature}`);
+
+  // create token-2022 mint with transfer fees
+  const feeMintLen = getMintLen([ExtensionType.TransferFeeConfig]);
+  const userFeeAta = getAssociatedTokenAddressSync(
+    feeMint,
+    user.publicKey,
+    false,
+    TOKEN_2022_PROGRAM_ID
+  );
+  const feeMintCreationTx = new Transaction().add(
+    SystemProgram.createAccount({
+      fromPubkey: user.publicKey,
+      newAccountPubkey: feeMint,
+      space: feeMintLen,
+      lamports: await connection.getMinimumBalanceForRentExemption(feeMintLen),
+      programId: TOKEN_2022_PROGRAM_ID,
+    }),
+    createInitializeTransferFeeConfigInstruction(
+      feeMint,
+      user.publicKey,
+      user.publicKey,
+      FEE_MINT_TRANSFER_FEE_BPS,
+      BigInt(1_000_000 * 1_000_000),
+      TOKEN_2022_PROGRAM_ID
+    ),
+    createInitializeMint2Instruction(
+      feeMint,
+      6,
+      user.publicKey,
+      user.publicKey,
+      TOKEN_2022_PROGRAM_ID
+    ),
+    createAssociatedTokenAccountIdempotentInstruction(
+      user.publicKey,
+      userFeeAta,
+      user.publicKey,
+      feeMint,
+      TOKEN_2022_PROGRAM_ID
+    ),
+    createMintToInstruction(
+      feeMint,
+      userFeeAta,
+      user.publicKey,
+      1000 * 1_000_000,
+      [],
+      TOKEN_2022_PROGRAM_ID
+    ),
+
+    // create ata for admin
+    createAssociatedTokenAccountIdempotentInstruction(
+      user.publicKey,
+      getAssociatedTokenAddressSync(
+        feeMint,
+        admin.publicKey,
+        false,
+        TOKEN_2022_PROGRAM_ID
+      ),
+      admin.publicKey,
+      feeMint,
+      TOKEN_2022_PROGRAM_ID
+    )
+  );
+  const feeMintSignature = await sendAndConfirmTransaction(
+    connection,
+    feeMintCreationTx,
+    [user, feeMintKeypair]
+  );
+  log(`transfer fee mint setup: ${feeMintSignature}`);
 }
@@ -77,9 +77,15 @@ export function getTokenBalanceDelta(
     transaction,
   }: Pick<ParsedTransactionWithMeta, "meta" | "transaction">,
   mint: PublicKey,
-  owner: PublicKey
+  owner: PublicKey,
+  tokenProgram?: PublicKey
 ): bigint {
-  const ataAddress = getAssociatedTokenAddressSync(mint, owner, true);
+  const ataAddress = getAssociatedTokenAddressSync(
+    mint,
+    owner,
+    true,
+    tokenProgram
+  );
   const ataIndex = transaction.message.accountKeys.findIndex(({ pubkey }) =>
     pubkey.equals(ataAddress)
   );
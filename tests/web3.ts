@@ -5,6 +5,7 @@ export type Connection = web3.Connection;
 export type Keypair = web3.Keypair;
 export type ParsedTransactionWithMeta = web3.ParsedTransactionWithMeta;
 export type PublicKey = web3.PublicKey;
+export type TransactionInstruction = web3.TransactionInstruction;
 
 export const Keypair = web3.Keypair;
 export const PublicKey = web3.PublicKey;